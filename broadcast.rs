@@ -0,0 +1,209 @@
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use {ExternalSet, ItemId, ItemOwner};
+
+/// A callback registered with a Broadcaster.
+pub type Listener<E> = Box<dyn Fn(&E) + Send + Sync>;
+
+/// The owner of a listener registered with a Broadcaster. The listener is unsubscribed when
+/// this is dropped.
+pub type Subscription<'b, E> = ItemOwner<'b, Listener<E>>;
+
+/// Calls a set of subscribed callbacks with each emitted event.
+///
+/// Listeners may subscribe new listeners or drop subscriptions (including their own) from
+/// within a callback. Such changes take effect after the emit in progress completes; see
+/// ExternalSet for details.
+///
+/// If a listener panics, the panic propagates out of `emit` and the remaining listeners are not
+/// called for that event. The Broadcaster itself remains usable. Use `try_emit` to isolate
+/// listeners from each other's panics.
+pub struct Broadcaster<E> {
+    listeners: ExternalSet<Listener<E>>,
+    evict_on_panic: AtomicBool,
+}
+
+impl<E> Broadcaster<E> {
+    /// Create a Broadcaster with no listeners
+    pub fn new() -> Broadcaster<E> {
+        Broadcaster { listeners: ExternalSet::new(), evict_on_panic: AtomicBool::new(false) }
+    }
+
+    /// Register a callback to be called for each event, returning a Subscription that
+    /// unsubscribes it when dropped.
+    pub fn subscribe<'b, F>(&'b self, f: F) -> Subscription<'b, E>
+        where F: Fn(&E) + Send + Sync + 'static
+    {
+        self.listeners.insert(Box::new(f))
+    }
+
+    /// Call every listener with the event, in the order they subscribed.
+    pub fn emit(&self, event: &E) {
+        for listener in self.listeners.lock().iter() {
+            listener(event);
+        }
+    }
+
+    /// Call every listener except the one owned by `except` with the event, for example to
+    /// avoid echoing an event back to the client that sent it.
+    pub fn emit_except(&self, except: &Subscription<E>, event: &E) {
+        for listener in self.listeners.lock().others(except) {
+            listener(event);
+        }
+    }
+
+    /// Call every listener with the event, catching any panics so that one failing listener
+    /// does not prevent the others from being called.
+    ///
+    /// Returns the panics that occurred, in the order the listeners were called.
+    pub fn try_emit(&self, event: &E) -> Result<(), Vec<ListenerPanic>> {
+        self.emit_isolated(None, event)
+    }
+
+    /// Like `emit_except`, but catching panics as in `try_emit`.
+    pub fn try_emit_except(&self, except: &Subscription<E>, event: &E)
+        -> Result<(), Vec<ListenerPanic>>
+    {
+        self.emit_isolated(Some(except.id()), event)
+    }
+
+    fn emit_isolated(&self, except: Option<ItemId>, event: &E) -> Result<(), Vec<ListenerPanic>> {
+        let mut panics = Vec::new();
+        let listeners = self.listeners.lock();
+        for (id, listener) in listeners.iter_with_ids().filter(|&(id, _)| Some(id) != except) {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| listener(event))) {
+                if self.evict_on_panic.load(Ordering::Relaxed) {
+                    self.listeners.evict(id);
+                }
+                panics.push(ListenerPanic { id, payload });
+            }
+        }
+        if panics.is_empty() { Ok(()) } else { Err(panics) }
+    }
+
+    /// If enabled, a listener that panics during `try_emit` is evicted, so that it is not
+    /// called for later events. Its Subscription reports `is_revoked()`.
+    pub fn set_evict_on_panic(&self, evict: bool) {
+        self.evict_on_panic.store(evict, Ordering::Relaxed);
+    }
+
+    /// The underlying set of listeners.
+    pub fn listeners(&self) -> &ExternalSet<Listener<E>> {
+        &self.listeners
+    }
+}
+
+/// A panic caught from a listener by `Broadcaster::try_emit`.
+pub struct ListenerPanic {
+    /// The ID of the listener that panicked, matching `Subscription::id()`.
+    pub id: ItemId,
+    /// The panic payload, as returned by `std::panic::catch_unwind`.
+    pub payload: Box<dyn Any + Send>,
+}
+
+impl ListenerPanic {
+    /// The panic message, if the payload is a string.
+    pub fn message(&self) -> Option<&str> {
+        if let Some(s) = self.payload.downcast_ref::<&'static str>() {
+            Some(s)
+        } else if let Some(s) = self.payload.downcast_ref::<String>() {
+            Some(s)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Debug for ListenerPanic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ListenerPanic")
+            .field("id", &self.id)
+            .field("message", &self.message())
+            .finish()
+    }
+}
+
+impl<E> Default for Broadcaster<E> {
+    fn default() -> Broadcaster<E> {
+        Broadcaster::new()
+    }
+}
+
+#[test]
+fn test_broadcast() {
+    use std::sync::{Arc, Mutex};
+
+    let b = Broadcaster::<u32>::new();
+    let log = Arc::new(Mutex::new(Vec::new()));
+
+    let l = log.clone();
+    let s1 = b.subscribe(move |&e| l.lock().unwrap().push((1, e)));
+    let l = log.clone();
+    let _s2 = b.subscribe(move |&e| l.lock().unwrap().push((2, e)));
+
+    b.emit(&10);
+    b.emit_except(&s1, &20);
+    drop(s1);
+    b.emit(&30);
+
+    assert_eq!(*log.lock().unwrap(), vec![(1, 10), (2, 10), (2, 20), (2, 30)]);
+}
+
+#[test]
+fn test_broadcast_unsubscribe_in_callback() {
+    use std::sync::{Arc, Mutex};
+
+    let b: &'static Broadcaster<u32> = Box::leak(Box::new(Broadcaster::new()));
+    let count = Arc::new(Mutex::new(0));
+    let sub: Arc<Mutex<Option<Subscription<u32>>>> = Arc::new(Mutex::new(None));
+
+    let (c, s) = (count.clone(), sub.clone());
+    *sub.lock().unwrap() = Some(b.subscribe(move |_| {
+        *c.lock().unwrap() += 1;
+        s.lock().unwrap().take();
+    }));
+
+    b.emit(&1);
+    b.emit(&2);
+    assert_eq!(*count.lock().unwrap(), 1);
+    assert_eq!(b.listeners().lock().iter().count(), 0);
+}
+
+#[test]
+fn test_broadcast_panic_isolation() {
+    use std::sync::{Arc, Mutex};
+
+    let b = Broadcaster::<u32>::new();
+    let log = Arc::new(Mutex::new(Vec::new()));
+
+    let l = log.clone();
+    let _s1 = b.subscribe(move |&e| l.lock().unwrap().push((1, e)));
+    let s2 = b.subscribe(|&e| if e > 1 { panic!("bad event {}", e) });
+    let l = log.clone();
+    let _s3 = b.subscribe(move |&e| l.lock().unwrap().push((3, e)));
+
+    assert!(b.try_emit(&1).is_ok());
+    let panics = b.try_emit(&2).unwrap_err();
+    assert_eq!(panics.len(), 1);
+    assert_eq!(panics[0].id, s2.id());
+    assert_eq!(panics[0].message(), Some("bad event 2"));
+    assert!(b.try_emit_except(&s2, &3).is_ok());
+
+    assert_eq!(*log.lock().unwrap(), vec![(1, 1), (3, 1), (1, 2), (3, 2), (1, 3), (3, 3)]);
+}
+
+#[test]
+fn test_broadcast_evict_on_panic() {
+    let b = Broadcaster::<u32>::new();
+    b.set_evict_on_panic(true);
+    let s1 = b.subscribe(|_| panic!("always"));
+    let s2 = b.subscribe(|_| {});
+
+    assert_eq!(b.try_emit(&1).unwrap_err().len(), 1);
+    assert!(s1.is_revoked());
+    assert!(!s2.is_revoked());
+    assert!(b.try_emit(&2).is_ok());
+}
@@ -0,0 +1,320 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::sync::mpsc::{RecvError, RecvTimeoutError, TryRecvError};
+use std::time::{Duration, Instant};
+
+use {EitherOwner, ExternalSet};
+
+/// What `ChannelHub::publish` does when a subscriber's queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Discard the oldest queued message to make room for the new one.
+    DropOldest,
+    /// Discard the new message.
+    DropNewest,
+    /// Disconnect the subscriber. It receives the messages already queued, and then
+    /// `Disconnected`.
+    Disconnect,
+    /// Wait up to the specified duration for the subscriber to make room, and then discard the
+    /// new message. Other subscribers do not receive the message until the wait is over.
+    ///
+    /// The publisher waits while holding the hub's read lock, so subscribing and unsubscribing
+    /// on other threads also wait, for up to the timeout for each full queue.
+    Block(Duration),
+}
+
+/// An in-process publish/subscribe hub. Each subscriber owns a bounded queue that receives a
+/// clone of every published message.
+///
+/// When the hub is dropped, every subscriber is disconnected.
+pub struct ChannelHub<T> {
+    queues: Arc<ExternalSet<Queue<T>>>,
+}
+
+struct Queue<T> {
+    state: Mutex<QueueState<T>>,
+    /// Signalled when a message is queued or the queue is disconnected.
+    readable: Condvar,
+    /// Signalled when a message is received or the receiver is dropped.
+    writable: Condvar,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+struct QueueState<T> {
+    messages: VecDeque<T>,
+    disconnected: bool,
+    dropped: u64,
+}
+
+impl<T> Queue<T> {
+    fn state(&self) -> MutexGuard<'_, QueueState<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn new(capacity: usize, overflow: Overflow) -> Queue<T> {
+        assert!(capacity > 0, "ChannelHub queue capacity must be nonzero");
+        Queue {
+            state: Mutex::new(QueueState {
+                messages: VecDeque::with_capacity(capacity),
+                disconnected: false,
+                dropped: 0,
+            }),
+            readable: Condvar::new(),
+            writable: Condvar::new(),
+            capacity,
+            overflow,
+        }
+    }
+
+    /// Stop accepting messages, waking the receiver and any blocked publisher.
+    fn disconnect(&self) {
+        self.state().disconnected = true;
+        self.readable.notify_all();
+        self.writable.notify_all();
+    }
+
+    /// Queue a message, applying the overflow policy. Returns true if the message was queued.
+    fn push(&self, message: T) -> bool {
+        let mut state = self.state();
+        if state.disconnected {
+            return false;
+        }
+
+        if state.messages.len() >= self.capacity {
+            match self.overflow {
+                Overflow::DropOldest => {
+                    state.messages.pop_front();
+                    state.dropped += 1;
+                }
+                Overflow::DropNewest => {
+                    state.dropped += 1;
+                    return false;
+                }
+                Overflow::Disconnect => {
+                    state.disconnected = true;
+                    self.readable.notify_all();
+                    return false;
+                }
+                Overflow::Block(timeout) => {
+                    let deadline = Instant::now() + timeout;
+                    while state.messages.len() >= self.capacity && !state.disconnected {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            break;
+                        }
+                        state = self.writable.wait_timeout(state, deadline - now)
+                            .unwrap_or_else(PoisonError::into_inner).0;
+                    }
+                    if state.disconnected {
+                        return false;
+                    }
+                    if state.messages.len() >= self.capacity {
+                        state.dropped += 1;
+                        return false;
+                    }
+                }
+            }
+        }
+
+        state.messages.push_back(message);
+        self.readable.notify_one();
+        true
+    }
+}
+
+impl<T> ChannelHub<T> {
+    /// Create a ChannelHub with no subscribers
+    pub fn new() -> ChannelHub<T> {
+        ChannelHub { queues: Arc::new(ExternalSet::new()) }
+    }
+
+    /// Subscribe to messages published after this call, returning a Receiver with a queue of up
+    /// to `capacity` messages. When the queue is full, new messages are handled according to
+    /// `overflow`.
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn subscribe(&self, capacity: usize, overflow: Overflow) -> Receiver<'_, T> {
+        let queue = Queue::new(capacity, overflow);
+        Receiver { queue: EitherOwner::Borrowed(self.queues.insert(queue)) }
+    }
+
+    /// Like `subscribe`, but the Receiver does not borrow the hub, so it can be moved to another
+    /// thread or stored in a struct. If the hub is dropped first, the receiver is disconnected.
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn subscribe_owned(&self, capacity: usize, overflow: Overflow) -> Receiver<'static, T>
+        where T: 'static
+    {
+        let queue = Queue::new(capacity, overflow);
+        Receiver { queue: EitherOwner::Arc(self.queues.insert_arc(queue)) }
+    }
+
+    /// Send a clone of the message to every subscriber. Returns the number of subscribers whose
+    /// queue accepted the message.
+    pub fn publish(&self, message: T) -> usize where T: Clone {
+        self.queues.lock().iter().filter(|queue| queue.push(message.clone())).count()
+    }
+}
+
+impl<T> Drop for ChannelHub<T> {
+    fn drop(&mut self) {
+        for queue in self.queues.lock().iter() {
+            queue.disconnect();
+        }
+    }
+}
+
+impl<T> Default for ChannelHub<T> {
+    fn default() -> ChannelHub<T> {
+        ChannelHub::new()
+    }
+}
+
+/// The receiving end of a ChannelHub subscription. The subscription ends when this is dropped.
+pub struct Receiver<'h, T: 'h> {
+    queue: EitherOwner<'h, Queue<T>>,
+}
+
+impl<'h, T> Receiver<'h, T> {
+    /// Wait for a message. Returns an error once the subscriber has been disconnected and all
+    /// queued messages have been received.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let mut state = self.queue.state();
+        loop {
+            if let Some(message) = state.messages.pop_front() {
+                self.queue.writable.notify_one();
+                return Ok(message);
+            }
+            if state.disconnected {
+                return Err(RecvError);
+            }
+            state = self.queue.readable.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Wait up to `timeout` for a message.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.queue.state();
+        loop {
+            if let Some(message) = state.messages.pop_front() {
+                self.queue.writable.notify_one();
+                return Ok(message);
+            }
+            if state.disconnected {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            state = self.queue.readable.wait_timeout(state, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner).0;
+        }
+    }
+
+    /// Receive a message if one is queued, without waiting.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = self.queue.state();
+        match state.messages.pop_front() {
+            Some(message) => {
+                self.queue.writable.notify_one();
+                Ok(message)
+            }
+            None if state.disconnected => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Iterate over messages, waiting for each one, until the subscriber is disconnected.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        ::std::iter::from_fn(move || self.recv().ok())
+    }
+
+    /// Returns true if the subscriber was disconnected by the `Disconnect` overflow policy.
+    /// Queued messages can still be received.
+    pub fn is_disconnected(&self) -> bool {
+        self.queue.state().disconnected
+    }
+
+    /// The number of messages that were discarded because this subscriber's queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.queue.state().dropped
+    }
+}
+
+impl<'h, T> Drop for Receiver<'h, T> {
+    fn drop(&mut self) {
+        // Wake a publisher blocked on this queue so it does not hold the hub's lock while the
+        // subscription is removed.
+        self.queue.disconnect();
+    }
+}
+
+#[test]
+fn test_channel_overflow() {
+    let hub = ChannelHub::<u32>::new();
+    let oldest = hub.subscribe(2, Overflow::DropOldest);
+    let newest = hub.subscribe(2, Overflow::DropNewest);
+    let disconnect = hub.subscribe(2, Overflow::Disconnect);
+
+    for i in 0..3 {
+        hub.publish(i);
+    }
+    assert_eq!(hub.publish(3), 1);
+
+    assert_eq!(oldest.iter().take(2).collect::<Vec<_>>(), vec![2, 3]);
+    assert_eq!(oldest.dropped(), 2);
+    assert_eq!(newest.iter().take(2).collect::<Vec<_>>(), vec![0, 1]);
+    assert_eq!(newest.dropped(), 2);
+
+    assert!(disconnect.is_disconnected());
+    assert_eq!(disconnect.iter().collect::<Vec<_>>(), vec![0, 1]);
+    assert_eq!(disconnect.try_recv(), Err(TryRecvError::Disconnected));
+
+    drop(oldest);
+    assert_eq!(hub.publish(4), 1);
+    assert_eq!(newest.try_recv(), Ok(4));
+    assert_eq!(newest.try_recv(), Err(TryRecvError::Empty));
+}
+
+#[test]
+fn test_channel_block() {
+    use std::sync::Arc;
+    use std::thread;
+
+    let hub = Arc::new(ChannelHub::<u32>::new());
+    let rx = hub.subscribe(1, Overflow::Block(Duration::from_secs(10)));
+    hub.publish(1);
+
+    let h = hub.clone();
+    let t = thread::spawn(move || h.publish(2));
+
+    assert_eq!(rx.recv_timeout(Duration::from_secs(10)), Ok(1));
+    assert_eq!(rx.recv_timeout(Duration::from_secs(10)), Ok(2));
+    assert_eq!(t.join().unwrap(), 1);
+    drop(rx);
+
+    let rx = hub.subscribe(1, Overflow::Block(Duration::from_millis(10)));
+    assert_eq!(hub.publish(3), 1);
+    assert_eq!(hub.publish(4), 0);
+    assert_eq!(rx.dropped(), 1);
+    assert_eq!(rx.try_recv(), Ok(3));
+    assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+}
+
+#[test]
+fn test_channel_owned() {
+    use std::thread;
+
+    let hub = ChannelHub::<u32>::new();
+    let rx = hub.subscribe_owned(4, Overflow::DropNewest);
+    let t = thread::spawn(move || rx.iter().collect::<Vec<_>>());
+    assert_eq!(hub.publish(1), 1);
+    assert_eq!(hub.publish(2), 1);
+
+    // Dropping the hub disconnects the receiver once it has received the queued messages
+    drop(hub);
+    assert_eq!(t.join().unwrap(), vec![1, 2]);
+}
@@ -1,120 +1,1682 @@
-use std::collections::HashSet;
-use std::sync::RwLock;
+use std::any::Any;
+use std::borrow::Borrow;
+use std::cell::UnsafeCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::hash_map::RandomState;
+use std::sync::{Arc, Condvar, LockResult, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard};
+use std::sync::Weak;
+use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+use std::mem::{self, ManuallyDrop};
+use std::error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
 use std::marker::PhantomData;
 
+#[cfg(feature = "async")]
+extern crate futures_core;
+
+mod snapshot;
+pub use snapshot::{SnapshotSet, Snapshot, SnapshotIter, SnapshotItemOwner};
+
+mod broadcast;
+pub use broadcast::{Broadcaster, Listener, ListenerPanic, Subscription};
+
+mod channel;
+pub use channel::{ChannelHub, Overflow, Receiver};
+
+mod map;
+pub use map::{ExternalMap, ExternalMapReadGuard, ExternalMapIter, ExternalMapValues, MapItemOwner};
+
+mod locked;
+pub use locked::{LockedSet, LockedItemOwner, LockedIter};
+
+#[cfg(feature = "async")]
+mod stream;
+#[cfg(feature = "async")]
+pub use stream::{StreamHub, StreamSubscription};
+
 /// A thread-safe set of references to items owned externally by an ItemOwner.
 ///
 /// When an ItemOwner is dropped or its .take() method is called, the
 /// item is removed from the collection.
+///
+/// Items are iterated in order of priority, highest first, and then in the order they were
+/// inserted. Items inserted with `insert` have priority 0.
+///
+/// An ItemOwner may be dropped while the same thread is iterating over the collection (for
+/// example, a listener unsubscribing itself from within a callback). The removal is deferred
+/// until the last read guard on the collection is released.
+///
+/// Similarly, items inserted by a thread that holds a read guard are queued, and become visible
+/// once the last read guard on the collection is released. Iterations in progress at the time of
+/// the insertion, and any read guards taken before that point, do not see the new item.
 pub struct ExternalSet<T> {
-    items: RwLock<HashSet<*mut T>>
+    /// Guarded by `lock`.
+    items: UnsafeCell<Items<T>>,
+    readers: Mutex<Readers<T>>,
+    /// Declared after `readers`, which holds read guards on it.
+    lock: RwLock<()>,
+    next_seq: AtomicU64,
+    poison_policy: PoisonPolicy,
+    hooks: RwLock<Hooks<T>>,
+    membership: Mutex<Membership>,
+    membership_changed: Condvar,
+    /// Signalled when the last hook call for an item returns, for owners waiting in `take()` or
+    /// `update()`.
+    pins: Mutex<()>,
+    unpinned: Condvar,
+    closed: AtomicBool,
+    len: AtomicUsize,
+    /// Identifies the collection for `ItemRef`.
+    uid: u64,
+}
+
+static NEXT_UID: AtomicU64 = AtomicU64::new(0);
+
+/// How an ExternalSet handles its lock being poisoned by a thread that panicked while holding it.
+///
+/// Removing an item, whether by dropping its owner or by `take()`, always recovers from poison
+/// and never panics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoisonPolicy {
+    /// Return `Error::Poisoned` from the `try_` methods, and panic in the others.
+    Propagate,
+    /// Ignore the poison and continue to use the collection.
+    Recover,
+}
+
+/// An error returned by the fallible methods of ExternalSet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The lock was poisoned by a panic on another thread.
+    Poisoned,
+    /// The collection was closed, and no longer accepts new items.
+    Closed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Poisoned => write!(f, "ExternalSet lock poisoned by a panic"),
+            Error::Closed => write!(f, "ExternalSet is closed"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// The error returned by `ExternalSet::insert_unique` when an equal item is already in the
+/// collection. It contains the item that was not inserted.
+#[derive(PartialEq, Eq)]
+pub struct DuplicateError<T>(pub T);
+
+impl<T> DuplicateError<T> {
+    /// The item that was not inserted.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> fmt::Debug for DuplicateError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("DuplicateError { .. }")
+    }
+}
+
+impl<T> fmt::Display for DuplicateError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an equal item is already in the ExternalSet")
+    }
+}
+
+impl<T> error::Error for DuplicateError<T> {}
+
+fn poison<G>(result: LockResult<G>, policy: PoisonPolicy) -> Result<G, Error> {
+    match result {
+        Ok(guard) => Ok(guard),
+        Err(e) => match policy {
+            PoisonPolicy::Propagate => Err(Error::Poisoned),
+            PoisonPolicy::Recover => Ok(e.into_inner()),
+        }
+    }
+}
+
+fn expect<R>(result: Result<R, Error>) -> R {
+    match result {
+        Ok(r) => r,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+/// Resume a panic caught from a hook, unless the thread is already unwinding (for example, when
+/// an owner is dropped during a panic), since a second panic would abort the process.
+fn resume_hook_panic(payload: Box<dyn Any + Send>) {
+    if !thread::panicking() {
+        panic::resume_unwind(payload);
+    }
+}
+
+/// An item, allocated when it is inserted and freed when its owner is dropped.
+struct Node<T> {
+    value: T,
+    /// The `uid` of the collection the item was inserted into.
+    collection: u64,
+    /// Set when the item is evicted from the collection by `evict` or `retain`.
+    revoked: AtomicBool,
+    /// One reference for the owner, and one for each hook call in progress for the item, which
+    /// may be on a thread other than the owner's. The last reference frees the node.
+    refs: AtomicUsize,
+}
+
+/// Add a reference to a node for a hook call. This is done while holding the write lock, so that
+/// the owner cannot free the node first.
+unsafe fn pin<T>(ptr: *mut Node<T>) {
+    (*ptr).refs.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Drop a reference to a node, freeing it if it was the last. Returns true if only the owner's
+/// reference remains.
+unsafe fn unpin<T>(ptr: *mut Node<T>) -> bool {
+    match (*ptr).refs.fetch_sub(1, Ordering::Release) {
+        1 => {
+            atomic::fence(Ordering::Acquire);
+            drop(Box::from_raw(ptr));
+            false
+        }
+        refs => refs == 2,
+    }
+}
+
+/// Returns true if hooks are still being called for the item on other threads.
+unsafe fn is_pinned<T>(ptr: *mut Node<T>) -> bool {
+    (*ptr).refs.load(Ordering::Acquire) > 1
+}
+
+/// Items keyed by priority and insertion sequence number, so that iteration visits higher
+/// priority items first, and items of equal priority in insertion order.
+struct Items<T> {
+    map: BTreeMap<Key, *mut Node<T>>,
+    /// The current key of each item, by sequence number.
+    keys: HashMap<u64, Key>,
+    /// Secondary indexes added by `add_index`.
+    indexes: Vec<IndexTable<T>>,
+    /// The index of items by value, and its hasher, created by the first `insert_unique`.
+    value_index: Option<(usize, RandomState)>,
+}
+
+/// The sequence numbers of items, by the hash of the key extracted from them by an Index.
+struct IndexTable<T> {
+    hash: Box<dyn Fn(&T) -> u64 + Send + Sync>,
+    buckets: HashMap<u64, BTreeSet<u64>>,
+}
+
+impl<T> IndexTable<T> {
+    fn insert(&mut self, seq: u64, item: &T) {
+        self.buckets.entry((self.hash)(item)).or_default().insert(seq);
+    }
+
+    fn remove(&mut self, seq: u64, item: &T) {
+        let hash = (self.hash)(item);
+        let now_empty = match self.buckets.get_mut(&hash) {
+            Some(seqs) => { seqs.remove(&seq); seqs.is_empty() }
+            None => false,
+        };
+        if now_empty {
+            self.buckets.remove(&hash);
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    priority: Reverse<i32>,
+    seq: u64,
+}
+
+/// The threads holding read guards on the collection, and the changes that were requested by
+/// those threads while they held them.
+struct Readers<T> {
+    threads: HashMap<ThreadId, Reader>,
+    pending: Vec<Pending<T>>,
+}
+
+/// The read lock held by a thread, shared by all of its read guards so that taking another
+/// guard cannot deadlock behind a waiting writer.
+struct Reader {
+    guards: usize,
+    /// Released with the thread's last read guard, while that guard still borrows the
+    /// collection. It is leaked if a guard is leaked, since the collection may then be moved.
+    lock: ManuallyDrop<RwLockReadGuard<'static, ()>>,
+}
+
+/// A change to the collection deferred until no read guards are held.
+enum Pending<T> {
+    /// Add the item.
+    Insert(Key, *mut Node<T>),
+    /// Remove the item and free it.
+    Remove(Key, *mut Node<T>),
+    /// Move the item from the first key to the second.
+    Move(Key, Key),
+    /// Remove the item with the sequence number and mark it as revoked, leaving it to its owner.
+    Evict(u64),
+}
+
+/// A membership change that has been applied to the collection, to be reported to the hooks
+/// once the lock is released.
+enum Event<T> {
+    /// The item was added.
+    Inserted(*mut Node<T>),
+    /// The item was removed, and the owner's reference must be dropped after the hooks are called.
+    Removed(*mut Node<T>),
+    /// The item was removed by `take()` or evicted, and remains with its owner.
+    Detached(*mut Node<T>),
+    /// The owner of an evicted item was dropped, so the owner's reference must be dropped.
+    Freed(*mut Node<T>),
+}
+
+/// The number of items and a count of membership changes, updated in the same order as changes
+/// are applied, for threads waiting on the membership of the collection.
+struct Membership {
+    len: usize,
+    generation: u64,
+    #[cfg(feature = "async")]
+    wakers: Vec<::std::task::Waker>,
+}
+
+type Hook<T> = Arc<dyn Fn(&T) + Send + Sync>;
+
+/// Callbacks registered with `on_insert` and `on_remove`.
+struct Hooks<T> {
+    insert: Vec<Hook<T>>,
+    remove: Vec<Hook<T>>,
+    close: Vec<Hook<T>>,
+}
+
+impl<T> Items<T> {
+    fn contains(&self, seq: u64, ptr: *mut Node<T>) -> bool {
+        self.keys.get(&seq).and_then(|key| self.map.get(key)) == Some(&ptr)
+    }
+
+    fn insert(&mut self, key: Key, ptr: *mut Node<T>) {
+        self.map.insert(key, ptr);
+        self.keys.insert(key.seq, key);
+        for index in &mut self.indexes {
+            index.insert(key.seq, unsafe { &(*ptr).value });
+        }
+    }
+
+    fn remove(&mut self, key: Key) -> Option<*mut Node<T>> {
+        let ptr = self.map.remove(&key);
+        if let Some(ptr) = ptr {
+            self.keys.remove(&key.seq);
+            for index in &mut self.indexes {
+                index.remove(key.seq, unsafe { &(*ptr).value });
+            }
+        }
+        ptr
+    }
+
+    fn get(&self, seq: u64) -> Option<*mut Node<T>> {
+        self.keys.get(&seq).and_then(|key| self.map.get(key)).cloned()
+    }
+
+    /// Run `f` on the value of an item, updating the indexes if it is in the collection. A panic
+    /// in `f` is caught, so that it does not poison the lock, and returned once the item has been
+    /// indexed again.
+    fn modify<R, F>(&mut self, seq: u64, ptr: *mut Node<T>, f: F) -> thread::Result<R>
+        where F: FnOnce(&mut T) -> R
+    {
+        let present = self.contains(seq, ptr);
+        if present {
+            for index in &mut self.indexes {
+                index.remove(seq, unsafe { &(*ptr).value });
+            }
+        }
+        let ret = panic::catch_unwind(AssertUnwindSafe(|| f(unsafe { &mut (*ptr).value })));
+        if present {
+            for index in &mut self.indexes {
+                index.insert(seq, unsafe { &(*ptr).value });
+            }
+        }
+        ret
+    }
+
+    /// Add an index, built from the current items, returning its number.
+    fn add_index(&mut self, mut table: IndexTable<T>) -> usize {
+        for (key, &ptr) in &self.map {
+            table.insert(key.seq, unsafe { &(*ptr).value });
+        }
+        self.indexes.push(table);
+        self.indexes.len() - 1
+    }
+
+    /// Returns true if an item equal to `value` is in the collection, or None if there is no
+    /// value index to look it up in.
+    fn contains_value(&self, value: &T) -> Option<bool> where T: Hash + Eq {
+        let (number, ref hasher) = *self.value_index.as_ref()?;
+        Some(match self.indexes[number].buckets.get(&hasher.hash_one(value)) {
+            Some(seqs) => seqs.iter()
+                .filter_map(|&seq| self.get(seq))
+                .any(|ptr| unsafe { &(*ptr).value } == value),
+            None => false,
+        })
+    }
+
+    /// Apply a change, collecting the resulting events.
+    fn apply(&mut self, change: Pending<T>, events: &mut Vec<Event<T>>) {
+        match change {
+            Pending::Insert(key, ptr) => {
+                self.insert(key, ptr);
+                events.push(Event::Inserted(ptr));
+            }
+            Pending::Remove(key, ptr) => {
+                match self.remove(key) {
+                    Some(_) => events.push(Event::Removed(ptr)),
+                    None => events.push(Event::Freed(ptr)),
+                }
+            }
+            Pending::Evict(seq) => {
+                if let Some(ptr) = self.keys.get(&seq).cloned().and_then(|key| self.remove(key)) {
+                    unsafe { (*ptr).revoked.store(true, Ordering::SeqCst) };
+                    events.push(Event::Detached(ptr));
+                }
+            }
+            Pending::Move(old, new) => {
+                if let Some(ptr) = self.map.remove(&old) {
+                    self.map.insert(new, ptr);
+                    self.keys.insert(new.seq, new);
+                }
+            }
+        }
+    }
 }
 
 impl<T> ExternalSet<T> {
     /// Create an empty ExternalSet
     pub fn new() -> ExternalSet<T> {
-        ExternalSet { items: RwLock::new(HashSet::new()) }
+        ExternalSet::with_poison_policy(PoisonPolicy::Propagate)
+    }
+
+    /// Create an empty ExternalSet that handles a poisoned lock according to `policy`.
+    pub fn with_poison_policy(policy: PoisonPolicy) -> ExternalSet<T> {
+        ExternalSet {
+            items: UnsafeCell::new(Items {
+                map: BTreeMap::new(),
+                keys: HashMap::new(),
+                indexes: Vec::new(),
+                value_index: None,
+            }),
+            readers: Mutex::new(Readers { threads: HashMap::new(), pending: Vec::new() }),
+            lock: RwLock::new(()),
+            next_seq: AtomicU64::new(0),
+            poison_policy: policy,
+            hooks: RwLock::new(Hooks { insert: Vec::new(), remove: Vec::new(), close: Vec::new() }),
+            membership: Mutex::new(Membership {
+                len: 0,
+                generation: 0,
+                #[cfg(feature = "async")]
+                wakers: Vec::new(),
+            }),
+            membership_changed: Condvar::new(),
+            pins: Mutex::new(()),
+            unpinned: Condvar::new(),
+            closed: AtomicBool::new(false),
+            len: AtomicUsize::new(0),
+            uid: NEXT_UID.fetch_add(1, Ordering::Relaxed),
+        }
     }
 
     /// Add an item to the collection, returning an ItemOwner to own it.
     /// When the ItemOwner is dropped, the value will be removed from the collection.
+    ///
+    /// Panics if the collection is closed, or if the lock is poisoned and the poison policy is
+    /// `Propagate`.
     pub fn insert<'s>(&'s self, item: T) -> ItemOwner<'s, T> {
-        let ptr = Box::into_raw(Box::new(item));
-        self.items.write().unwrap().insert(ptr);
-        ItemOwner { collection: self, ptr: ptr, _marker: PhantomData }
+        self.insert_with_priority(item, 0)
+    }
+
+    /// Add an item to the collection, returning an ItemOwner to own it, or an error if the
+    /// collection is closed, or if the lock is poisoned and the poison policy is `Propagate`.
+    pub fn try_insert<'s>(&'s self, item: T) -> Result<ItemOwner<'s, T>, Error> {
+        Ok(ItemOwner { collection: self, item: self.insert_ptr(item, 0)? })
+    }
+
+    /// Add an item to the collection with the specified priority, returning an ItemOwner to own
+    /// it. Items with a higher priority are iterated before items with a lower priority.
+    pub fn insert_with_priority<'s>(&'s self, item: T, priority: i32) -> ItemOwner<'s, T> {
+        ItemOwner { collection: self, item: expect(self.insert_ptr(item, priority)) }
+    }
+
+    /// Add an item to a collection shared by an `Arc`, returning an ArcItemOwner to own it.
+    ///
+    /// Unlike an ItemOwner, the ArcItemOwner is not bound to a borrow of the collection, so it
+    /// can be stored in structs, moved to other threads, or returned from functions. It keeps
+    /// the collection alive until it is dropped.
+    pub fn insert_arc(self: &Arc<Self>, item: T) -> ArcItemOwner<T> {
+        expect(self.try_insert_arc(item))
+    }
+
+    /// Add an item to a collection shared by an `Arc`, returning an ArcItemOwner to own it, or
+    /// an error if the collection is closed, or if the lock is poisoned and the poison policy is
+    /// `Propagate`.
+    pub fn try_insert_arc(self: &Arc<Self>, item: T) -> Result<ArcItemOwner<T>, Error> {
+        Ok(ArcItemOwner { collection: self.clone(), item: self.insert_ptr(item, 0)? })
+    }
+
+    /// Add an item to a collection shared by an `Arc`, returning a WeakItemOwner to own it.
+    ///
+    /// The WeakItemOwner does not keep the collection alive. If the collection is dropped
+    /// first, the item is simply dropped along with its owner.
+    pub fn insert_weak(self: &Arc<Self>, item: T) -> WeakItemOwner<T> {
+        expect(self.try_insert_weak(item))
+    }
+
+    /// Add an item to a collection shared by an `Arc`, returning a WeakItemOwner to own it, or
+    /// an error if the collection is closed, or if the lock is poisoned and the poison policy is
+    /// `Propagate`.
+    pub fn try_insert_weak(self: &Arc<Self>, item: T) -> Result<WeakItemOwner<T>, Error> {
+        Ok(WeakItemOwner { collection: Arc::downgrade(self), item: self.insert_ptr(item, 0)? })
+    }
+
+    /// Add an item to the collection unless an equal item is already in it, returning an
+    /// ItemOwner to own it, or a DuplicateError containing the item.
+    ///
+    /// The first call builds an index of the items by value, which is then maintained on every
+    /// insertion and removal, so that the check and `ExternalSetReadGuard::contains_value` take
+    /// constant time. Items must not change their hash or equality while in the collection,
+    /// except through an owner's `replace` or `update`, which do not check for duplicates.
+    ///
+    /// Panics if the collection is closed, if the lock is poisoned and the poison policy is
+    /// `Propagate`, or if the current thread holds a read guard on the collection.
+    pub fn insert_unique<'s>(&'s self, item: T) -> Result<ItemOwner<'s, T>, DuplicateError<T>>
+        where T: Hash + Eq + 'static
+    {
+        expect(self.try_insert_unique(item))
+    }
+
+    /// Like `insert_unique`, but returns an error if the collection is closed, or if the lock is
+    /// poisoned and the poison policy is `Propagate`.
+    ///
+    /// Panics if the current thread holds a read guard on the collection.
+    pub fn try_insert_unique<'s>(&'s self, item: T)
+        -> Result<Result<ItemOwner<'s, T>, DuplicateError<T>>, Error>
+        where T: Hash + Eq + 'static
+    {
+        self.assert_not_reading("insert_unique()");
+        let item = self.new_node(item, 0);
+        let inserted = self.insert_node(item.key, item.ptr, |items| {
+            if items.value_index.is_none() {
+                let hasher = RandomState::new();
+                let hasher_ = hasher.clone();
+                let table = IndexTable {
+                    hash: Box::new(move |item| hasher_.hash_one(item)),
+                    buckets: HashMap::new(),
+                };
+                items.value_index = Some((items.add_index(table), hasher));
+            }
+            items.contains_value(item.value()) != Some(true)
+        });
+
+        match inserted {
+            Ok(true) => Ok(Ok(ItemOwner { collection: self, item })),
+            Ok(false) => Ok(Err(DuplicateError(unsafe { Box::from_raw(item.ptr) }.value))),
+            Err(e) => {
+                drop(unsafe { Box::from_raw(item.ptr) });
+                Err(e)
+            }
+        }
     }
 
     /// Lock the collection for iteration. References obtained from the iterator have the
     /// lifetime of the returned guard.
-    pub fn lock(&self) -> ExternalSetReadGuard<T> {
-        ExternalSetReadGuard(self.items.read().unwrap())
+    ///
+    /// A thread that already holds a read guard may take more; they share its read access, so
+    /// this does not wait for writers on other threads.
+    ///
+    /// Panics if the lock is poisoned and the poison policy is `Propagate`.
+    pub fn lock(&self) -> ExternalSetReadGuard<'_, T> {
+        expect(self.try_lock())
+    }
+
+    /// Lock the collection for iteration, or return an error if the lock is poisoned and the
+    /// poison policy is `Propagate`.
+    pub fn try_lock(&self) -> Result<ExternalSetReadGuard<'_, T>, Error> {
+        let id = thread::current().id();
+        if let Some(reader) = self.readers().threads.get_mut(&id) {
+            reader.guards += 1;
+            return Ok(ExternalSetReadGuard { collection: self });
+        }
+        let lock = poison(self.lock.read(), self.poison_policy)?;
+        // The guard is only dropped by `unlock_reader`, called by a read guard borrowing self.
+        let lock = unsafe {
+            mem::transmute::<RwLockReadGuard<'_, ()>, RwLockReadGuard<'static, ()>>(lock)
+        };
+        let reader = Reader { guards: 1, lock: ManuallyDrop::new(lock) };
+        self.readers().threads.insert(id, reader);
+        Ok(ExternalSetReadGuard { collection: self })
+    }
+
+    /// The policy for handling a poisoned lock.
+    pub fn poison_policy(&self) -> PoisonPolicy {
+        self.poison_policy
+    }
+
+    /// Register a callback to be called with each item added to the collection.
+    ///
+    /// The callback is called after the item becomes visible to new read guards, without
+    /// holding the collection's lock. For items inserted while the inserting thread held a read
+    /// guard, that is when the last read guard is released, possibly on another thread. Dropping
+    /// the item's owner meanwhile defers freeing the item until the callback returns, and
+    /// `take()` or `update()` on the owner waits for it, so they must not be called on that
+    /// owner from within the callback.
+    ///
+    /// If the callback panics, the remaining callbacks are still called, and the panic is then
+    /// resumed. An item whose insertion panics this way is removed again.
+    pub fn on_insert<F: Fn(&T) + Send + Sync + 'static>(&self, f: F) {
+        self.hooks.write().unwrap_or_else(PoisonError::into_inner).insert.push(Arc::new(f));
+    }
+
+    /// Register a callback to be called with each item removed from the collection, whether by
+    /// dropping its owner or by `take()`.
+    ///
+    /// The callback is called after the item is no longer visible to new read guards, without
+    /// holding the collection's lock, and before the item is dropped or returned. For an item
+    /// evicted on another thread, dropping its owner defers freeing the item until the callback
+    /// returns, and `take()` or `update()` on the owner waits for it, so they must not be called
+    /// on that owner from within the callback.
+    ///
+    /// If the callback panics, the remaining callbacks are still called for every removed item,
+    /// and the panic is then resumed, unless the thread is already panicking.
+    pub fn on_remove<F: Fn(&T) + Send + Sync + 'static>(&self, f: F) {
+        self.hooks.write().unwrap_or_else(PoisonError::into_inner).remove.push(Arc::new(f));
+    }
+
+    /// The number of items in the collection, without taking the lock.
+    ///
+    /// This is approximate: it may not yet reflect changes in progress on other threads, or
+    /// changes deferred while a read guard is held. Use `ExternalSetReadGuard::len` for an exact
+    /// count.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the collection is empty, with the same caveats as `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Block until the collection is empty, i.e. every owner has been dropped or taken.
+    ///
+    /// Panics if the current thread holds a read guard on the collection, since removals would
+    /// be deferred until it is released.
+    pub fn wait_empty(&self) {
+        self.assert_not_reading("wait_empty()");
+        let mut membership = self.membership();
+        while membership.len > 0 {
+            membership = self.membership_changed.wait(membership)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Block until the collection is empty, or until the timeout elapses. Returns true if the
+    /// collection is empty.
+    ///
+    /// Panics if the current thread holds a read guard on the collection.
+    pub fn wait_empty_timeout(&self, timeout: Duration) -> bool {
+        self.assert_not_reading("wait_empty_timeout()");
+        let deadline = Instant::now() + timeout;
+        let mut membership = self.membership();
+        while membership.len > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            membership = self.membership_changed.wait_timeout(membership, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner).0;
+        }
+        true
+    }
+
+    /// Block until `predicate` returns true. It is called with a read guard on the collection
+    /// immediately, and again after each insertion or removal.
+    ///
+    /// Panics if the current thread holds a read guard on the collection.
+    pub fn wait_until<F>(&self, predicate: F) where F: FnMut(&ExternalSetReadGuard<T>) -> bool {
+        self.wait_until_deadline(None, predicate);
+    }
+
+    /// Block until `predicate` returns true, or until the timeout elapses. Returns the last
+    /// result of the predicate.
+    ///
+    /// Panics if the current thread holds a read guard on the collection.
+    pub fn wait_until_timeout<F>(&self, timeout: Duration, predicate: F) -> bool
+        where F: FnMut(&ExternalSetReadGuard<T>) -> bool
+    {
+        self.wait_until_deadline(Some(Instant::now() + timeout), predicate)
+    }
+
+    fn wait_until_deadline<F>(&self, deadline: Option<Instant>, mut predicate: F) -> bool
+        where F: FnMut(&ExternalSetReadGuard<T>) -> bool
+    {
+        self.assert_not_reading("wait_until()");
+        loop {
+            // Any change after reading the generation is seen by the predicate or wakes the wait
+            let generation = self.membership().generation;
+            if predicate(&self.lock()) {
+                return true;
+            }
+
+            let mut membership = self.membership();
+            while membership.generation == generation {
+                membership = match deadline {
+                    None => self.membership_changed.wait(membership)
+                        .unwrap_or_else(PoisonError::into_inner),
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            return false;
+                        }
+                        self.membership_changed.wait_timeout(membership, deadline - now)
+                            .unwrap_or_else(PoisonError::into_inner).0
+                    }
+                };
+            }
+        }
+    }
+
+    /// Returns a future that completes when the collection is empty.
+    #[cfg(feature = "async")]
+    pub fn wait_empty_async(&self) -> WaitEmpty<'_, T> {
+        WaitEmpty { collection: self }
+    }
+
+    fn membership(&self) -> MutexGuard<'_, Membership> {
+        self.membership.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn assert_not_reading(&self, method: &str) {
+        assert!(!self.readers().threads.contains_key(&thread::current().id()),
+            "{} called while the current thread holds a read guard on the ExternalSet", method);
+    }
+
+    /// Close the collection. Further insertions fail with `Error::Closed`, while existing items
+    /// remain until their owners are dropped, and can still be iterated.
+    ///
+    /// On the first call, the callbacks registered with `on_close` are called with each item in
+    /// the collection, so that their owners can be told to wind down. No item is added after
+    /// that, so every item inserted before `close()` returns is seen by the callbacks.
+    ///
+    /// Panics if the current thread holds a read guard on the collection.
+    pub fn close(&self) {
+        self.assert_not_reading("close()");
+        // Set under the write lock, so that insertions check it atomically with adding the item
+        let first = expect(self.write(PoisonPolicy::Recover, |_, _| {
+            !self.closed.swap(true, Ordering::SeqCst)
+        }));
+        if !first {
+            return;
+        }
+
+        let on_close = self.hooks.read().unwrap_or_else(PoisonError::into_inner).close.clone();
+        if !on_close.is_empty() {
+            let guard = self.lock();
+            for item in guard.iter() {
+                for hook in &on_close { hook(item) }
+            }
+        }
+    }
+
+    /// Remove the item with the specified ID from the collection, if present. IDs of items in
+    /// other collections are ignored. The item's owner keeps
+    /// the item, but `is_revoked()` returns true and dropping the owner no longer affects the
+    /// collection.
+    ///
+    /// If the current thread holds a read guard, the eviction is deferred until the last read
+    /// guard is released, like the removal of an item whose owner is dropped.
+    pub fn evict(&self, id: ItemId) {
+        if id.collection == self.uid {
+            let _ = self.change(Pending::Evict(id.seq), PoisonPolicy::Recover);
+        }
+    }
+
+    /// Evict every item for which `keep` returns false, as with `evict`. Returns the number of
+    /// items evicted.
+    ///
+    /// `keep` is called while holding the collection's write lock, so it must not access the
+    /// collection. If it panics, no items are evicted, and the panic is resumed after the lock
+    /// is released, leaving it unpoisoned.
+    ///
+    /// Panics if the current thread holds a read guard on the collection.
+    pub fn retain<F: FnMut(&T) -> bool>(&self, mut keep: F) -> usize {
+        self.assert_not_reading("retain()");
+        let evicted = expect(self.write(self.poison_policy, |items, events| {
+            let evict = panic::catch_unwind(AssertUnwindSafe(|| {
+                items.map.iter()
+                    .filter(|&(_, &ptr)| !keep(unsafe { &(*ptr).value }))
+                    .map(|(key, _)| key.seq)
+                    .collect::<Vec<_>>()
+            }))?;
+            for &seq in &evict {
+                items.apply(Pending::Evict(seq), events);
+            }
+            Ok(evict.len())
+        }));
+        evicted.unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+
+    /// Add a secondary index on the key extracted from each item by `extract`, so that items
+    /// can be found by key with `ExternalSetReadGuard::find_by` without scanning the collection.
+    ///
+    /// The index is built from the current items, and is kept up to date as items are inserted
+    /// and removed. Several items may have the same key. The key extracted from an item must not
+    /// change while the item is in the collection, except through an owner's `replace` or
+    /// `update`.
+    ///
+    /// Panics if the current thread holds a read guard on the collection.
+    pub fn add_index<K, F>(&self, extract: F) -> Index<T, K>
+        where T: 'static, K: Hash + Eq + 'static, F: Fn(&T) -> K + Send + Sync + 'static
+    {
+        self.assert_not_reading("add_index()");
+        let extract: Arc<dyn Fn(&T) -> K + Send + Sync> = Arc::new(extract);
+        let hasher = RandomState::new();
+        let (extract_, hasher_) = (extract.clone(), hasher.clone());
+        let table = IndexTable {
+            hash: Box::new(move |item| hasher_.hash_one(extract_(item))),
+            buckets: HashMap::new(),
+        };
+
+        let number = expect(self.write(self.poison_policy, |items, _| items.add_index(table)));
+
+        Index { collection: self.uid, number, extract, hasher }
+    }
+
+    /// Returns true if `close()` has been called.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Register a callback to be called with each item in the collection when it is closed.
+    pub fn on_close<F: Fn(&T) + Send + Sync + 'static>(&self, f: F) {
+        self.hooks.write().unwrap_or_else(PoisonError::into_inner).close.push(Arc::new(f));
+    }
+
+    fn insert_ptr(&self, item: T, priority: i32) -> Result<Owned<T>, Error> {
+        let item = self.new_node(item, priority);
+        match self.insert_node(item.key, item.ptr, |_| true) {
+            Ok(_) => Ok(item),
+            Err(e) => {
+                drop(unsafe { Box::from_raw(item.ptr) });
+                Err(e)
+            }
+        }
+    }
+
+    /// Allocate a node for an item, with the next sequence number. The caller must insert it or
+    /// free it.
+    fn new_node(&self, item: T, priority: i32) -> Owned<T> {
+        let node = Node {
+            value: item,
+            collection: self.uid,
+            revoked: AtomicBool::new(false),
+            refs: AtomicUsize::new(1),
+        };
+        let ptr = Box::into_raw(Box::new(node));
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        Owned { key: Key { priority: Reverse(priority), seq }, ptr, _marker: PhantomData }
+    }
+
+    /// Add a node to the collection if it is not closed and `accept` returns true under the
+    /// write lock, returning whether it was added. If the current thread holds a read guard,
+    /// adding the node is deferred and `accept` is not called.
+    fn insert_node<F>(&self, key: Key, ptr: *mut Node<T>, accept: F) -> Result<bool, Error>
+        where F: FnOnce(&mut Items<T>) -> bool
+    {
+        {
+            let mut readers = self.readers();
+            if readers.threads.contains_key(&thread::current().id()) {
+                // `close()` cannot take the write lock while this thread holds a read guard, so
+                // the deferred insert is applied before the collection is closed
+                if self.is_closed() {
+                    return Err(Error::Closed);
+                }
+                readers.pending.push(Pending::Insert(key, ptr));
+                return Ok(true);
+            }
+        }
+        let (inserted, panic) = self.write_catching(self.poison_policy, |items, events| {
+            if self.is_closed() {
+                return Err(Error::Closed);
+            }
+            if !accept(items) {
+                return Ok(false);
+            }
+            items.apply(Pending::Insert(key, ptr), events);
+            Ok(true)
+        })?;
+        // The caller has no owner for the node yet, so remove it again before resuming a panic
+        // from an `on_insert` hook
+        if let Some(payload) = panic {
+            if !thread::panicking() {
+                self.release(key, ptr);
+                panic::resume_unwind(payload);
+            }
+        }
+        inserted
+    }
+
+    /// The bookkeeping for read guards and deferred changes is only modified by this module and
+    /// is always left consistent, so it is safe to use even if a thread panicked while holding
+    /// the lock.
+    fn readers(&self) -> MutexGuard<'_, Readers<T>> {
+        self.readers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Take the write lock and run `f`, after applying any changes that were deferred while
+    /// the collection was being read. Once the lock is released, the hooks are called for the
+    /// resulting events and removed items are freed. If a hook panics, the panic is resumed
+    /// once every event has been handled.
+    fn write<R, F>(&self, policy: PoisonPolicy, f: F) -> Result<R, Error>
+        where F: FnOnce(&mut Items<T>, &mut Vec<Event<T>>) -> R
+    {
+        let (ret, panic) = self.write_catching(policy, f)?;
+        if let Some(payload) = panic {
+            resume_hook_panic(payload);
+        }
+        Ok(ret)
+    }
+
+    /// Like `write`, but returning the first panic from a hook instead of resuming it.
+    fn write_catching<R, F>(&self, policy: PoisonPolicy, f: F)
+        -> Result<(R, Option<Box<dyn Any + Send>>), Error>
+        where F: FnOnce(&mut Items<T>, &mut Vec<Event<T>>) -> R
+    {
+        let mut events = Vec::new();
+        let ret = {
+            let _lock = poison(self.lock.write(), policy)?;
+            let items = unsafe { &mut *self.items.get() };
+            let pending = mem::take(&mut self.readers().pending);
+            for change in pending {
+                items.apply(change, &mut events);
+            }
+            let ret = f(items, &mut events);
+            for event in &events {
+                if let Event::Inserted(ptr) | Event::Detached(ptr) = *event {
+                    unsafe { pin(ptr) };
+                }
+            }
+            if !events.is_empty() {
+                let mut membership = self.membership();
+                membership.len = items.map.len();
+                membership.generation += 1;
+                self.len.store(items.map.len(), Ordering::Relaxed);
+            }
+            ret
+        };
+        let changed = !events.is_empty();
+        let panic = self.dispatch(events);
+        if changed {
+            self.notify_membership();
+        }
+        Ok((ret, panic))
+    }
+
+    /// Wake threads and tasks waiting on the membership, once removed items have been freed.
+    fn notify_membership(&self) {
+        #[cfg(feature = "async")]
+        let wakers = mem::take(&mut self.membership().wakers);
+        self.membership_changed.notify_all();
+        #[cfg(feature = "async")]
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Call the hooks for each event and drop the references taken for them. If a hook panics,
+    /// the remaining hooks and events are still processed, and the first panic is returned.
+    fn dispatch(&self, events: Vec<Event<T>>) -> Option<Box<dyn Any + Send>> {
+        if events.is_empty() {
+            return None;
+        }
+
+        // Clone the hook list so that hooks may register other hooks.
+        let (on_insert, on_remove) = {
+            let hooks = self.hooks.read().unwrap_or_else(PoisonError::into_inner);
+            (hooks.insert.clone(), hooks.remove.clone())
+        };
+
+        let mut panic = None;
+        let mut call = |hooks: &[Hook<T>], ptr: *mut Node<T>| {
+            for hook in hooks {
+                let value = unsafe { &(*ptr).value };
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| hook(value))) {
+                    panic.get_or_insert(payload);
+                }
+            }
+        };
+        for event in events {
+            match event {
+                Event::Inserted(ptr) => {
+                    call(&on_insert, ptr);
+                    unsafe { self.unpin(ptr) };
+                }
+                Event::Removed(ptr) | Event::Detached(ptr) => {
+                    call(&on_remove, ptr);
+                    unsafe { self.unpin(ptr) };
+                }
+                Event::Freed(ptr) => {
+                    unsafe { self.unpin(ptr) };
+                }
+            }
+        }
+        panic
+    }
+
+    /// Apply a change now, or defer it if the current thread holds a read guard, since taking
+    /// the write lock would deadlock.
+    fn change(&self, change: Pending<T>, policy: PoisonPolicy) -> Result<(), Error> {
+        {
+            let mut readers = self.readers();
+            if readers.threads.contains_key(&thread::current().id()) {
+                readers.pending.push(change);
+                return Ok(());
+            }
+        }
+        self.write(policy, |items, events| items.apply(change, events))
+    }
+
+    /// Remove the item from the collection and free it. This is called from `Drop`, so it
+    /// ignores poisoning rather than panicking.
+    fn release(&self, key: Key, ptr: *mut Node<T>) {
+        let _ = self.change(Pending::Remove(key, ptr), PoisonPolicy::Recover);
+    }
+
+    /// Remove the item from the collection without freeing it, so that it can be returned from
+    /// `take()`.
+    fn detach(&self, key: Key) {
+        self.assert_not_reading("take()");
+        let _ = self.write(PoisonPolicy::Recover, |items, events| {
+            if let Some(ptr) = items.remove(key) {
+                events.push(Event::Detached(ptr));
+            }
+        });
+    }
+
+    /// Modify an item under the write lock, so that readers see it either before or after the
+    /// change, once no hooks are being called for it on other threads. If `f` panics, the panic
+    /// is resumed after the lock is released, leaving it unpoisoned.
+    fn modify<R, F: FnOnce(&mut T) -> R>(&self, method: &str, key: Key, ptr: *mut Node<T>, f: F)
+        -> R
+    {
+        self.assert_not_reading(method);
+        let mut f = Some(f);
+        loop {
+            let ret = expect(self.write(PoisonPolicy::Recover, |items, _| {
+                if unsafe { is_pinned(ptr) } {
+                    return None;
+                }
+                let f = f.take().expect("f is only called once");
+                Some(items.modify(key.seq, ptr, f))
+            }));
+            match ret {
+                Some(Ok(ret)) => return ret,
+                Some(Err(payload)) => panic::resume_unwind(payload),
+                None => unsafe { self.wait_unpinned(ptr) },
+            }
+        }
+    }
+
+    /// Drop a hook call's reference to a node, waking an owner waiting for the hooks to return.
+    unsafe fn unpin(&self, ptr: *mut Node<T>) {
+        if unpin(ptr) {
+            let _pins = self.pins.lock().unwrap_or_else(PoisonError::into_inner);
+            self.unpinned.notify_all();
+        }
+    }
+
+    /// Block until no hooks are being called for an item that is no longer being dispatched.
+    unsafe fn wait_unpinned(&self, ptr: *mut Node<T>) {
+        let mut pins = self.pins.lock().unwrap_or_else(PoisonError::into_inner);
+        while is_pinned(ptr) {
+            pins = self.unpinned.wait(pins).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Move an item to a new position in the iteration order, returning its new key.
+    fn reprioritize(&self, key: Key, priority: i32) -> Key {
+        let new_key = Key { priority: Reverse(priority), seq: key.seq };
+        let _ = self.change(Pending::Move(key, new_key), PoisonPolicy::Recover);
+        new_key
+    }
+
+    /// Called when a read guard is released, to apply deferred changes once the last one is.
+    fn unlock_reader(&self) {
+        let (lock, flush) = {
+            let mut readers = self.readers();
+            let id = thread::current().id();
+            let remaining = match readers.threads.get_mut(&id) {
+                Some(reader) => { reader.guards -= 1; reader.guards }
+                None => 0,
+            };
+            let lock = match remaining {
+                0 => readers.threads.remove(&id).map(|reader| reader.lock),
+                _ => None,
+            };
+            (lock, readers.threads.is_empty() && !readers.pending.is_empty())
+        };
+        if let Some(lock) = lock {
+            drop(ManuallyDrop::into_inner(lock));
+        }
+        if flush {
+            let _ = self.write(PoisonPolicy::Recover, |_, _| ());
+        }
+    }
+}
+
+impl<T> Default for ExternalSet<T> {
+    fn default() -> ExternalSet<T> {
+        ExternalSet::new()
     }
 }
 
-unsafe impl<T: Sync> Sync for ExternalSet<T> {}
+// Removals deferred while a read guard is held are applied, and the removed items dropped, in
+// `unlock_reader` or `write` on whichever thread releases the last guard or next takes the write
+// lock. Items can therefore be dropped on a thread other than their owner's, so sharing the
+// collection requires `T: Send` as well as `T: Sync`.
+unsafe impl<T: Send + Sync> Sync for ExternalSet<T> {}
 unsafe impl<T: Send + Sync> Send for ExternalSet<T> {}
 
+/// Future returned by `ExternalSet::wait_empty_async`.
+#[cfg(feature = "async")]
+pub struct WaitEmpty<'c, T: 'c> {
+    collection: &'c ExternalSet<T>,
+}
+
+#[cfg(feature = "async")]
+impl<'c, T> ::std::future::Future for WaitEmpty<'c, T> {
+    type Output = ();
+
+    fn poll(self: ::std::pin::Pin<&mut Self>, cx: &mut ::std::task::Context)
+        -> ::std::task::Poll<()>
+    {
+        let mut membership = self.collection.membership();
+        if membership.len == 0 {
+            ::std::task::Poll::Ready(())
+        } else {
+            if !membership.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                membership.wakers.push(cx.waker().clone());
+            }
+            ::std::task::Poll::Pending
+        }
+    }
+}
+
 /// RAII structure used to iterate over the items in a ExternalSet, and unlock the collection
 /// when dropped.
-pub struct ExternalSetReadGuard<'c, T: 'c>(::std::sync::RwLockReadGuard<'c, HashSet<*mut T>>);
+pub struct ExternalSetReadGuard<'c, T: 'c> {
+    collection: &'c ExternalSet<T>,
+}
+
+impl<'c, T> ExternalSetReadGuard<'c, T> {
+    /// Iterate over references to items in the ExternalSet, in priority order and then
+    /// insertion order.
+    pub fn iter<'g: 'c>(&'g self) -> ExternalSetIter<'g, T> {
+        let items = self.items();
+        ExternalSetIter { iter: items.map.values(), except: None, remaining: items.map.len() }
+    }
+
+    /// Iterate over references to items in the ExternalSet, excluding the one owned by the
+    /// provided owner, which may be an ItemOwner, ArcItemOwner or WeakItemOwner.
+    ///
+    /// If the provided owner's item is not in the collection (e.g. it was inserted into another
+    /// collection), all items will be yielded by the iterator.
+    ///
+    /// Items are yielded in priority order and then insertion order.
+    pub fn others<'g: 'c, O: Owner<T>>(&'g self, except: &O) -> ExternalSetIter<'g, T> {
+        let items = self.items();
+        let except = self.find(except.id());
+        let remaining = items.map.len() - except.is_some() as usize;
+        ExternalSetIter { iter: items.map.values(), except, remaining }
+    }
+
+    /// Iterate over the IDs and references to items in the ExternalSet, in the same order as
+    /// `iter`.
+    pub fn iter_with_ids<'g: 'c>(&'g self) -> ExternalSetIdIter<'g, T> {
+        ExternalSetIdIter { iter: self.items().map.iter(), collection: self.collection.uid }
+    }
+
+    /// Get the item with the specified ID, if it is in this collection.
+    pub fn get(&self, id: ItemId) -> Option<&T> {
+        self.find(id).map(|ptr| unsafe { &(*ptr).value })
+    }
+
+    /// Get the item referred to by `item_ref`, if it is still in this collection.
+    pub fn resolve(&self, item_ref: &ItemRef<T>) -> Option<&T> {
+        self.get(item_ref.id)
+    }
+
+    /// The number of items in the collection.
+    pub fn len(&self) -> usize {
+        self.items().map.len()
+    }
+
+    /// Returns true if the collection contains no items.
+    pub fn is_empty(&self) -> bool {
+        self.items().map.is_empty()
+    }
+
+    /// Returns true if the item owned by `owner` is in this collection.
+    pub fn contains<O: Owner<T>>(&self, owner: &O) -> bool {
+        self.find(owner.id()).is_some()
+    }
+
+    /// Returns true if an item equal to `value` is in the collection.
+    ///
+    /// This takes constant time once `insert_unique` has been used on the collection, and
+    /// otherwise compares against every item.
+    pub fn contains_value(&self, value: &T) -> bool where T: Hash + Eq {
+        let items = self.items();
+        items.contains_value(value).unwrap_or_else(|| self.iter().any(|item| item == value))
+    }
+
+    /// Iterate over the items whose key in `index` equals `key`, in insertion order. As with
+    /// `HashMap::get`, `key` may be any borrowed form of the index's key type.
+    ///
+    /// Panics if `index` was created by a different collection.
+    pub fn find_by<'a, K, Q>(&'a self, index: &'a Index<T, K>, key: &'a Q) -> FindBy<'a, T, K, Q>
+        where K: Hash + Eq + Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        assert!(index.collection == self.collection.uid,
+            "find_by() called with an Index from a different ExternalSet");
+        let items = self.items();
+        let seqs = items.indexes[index.number].buckets.get(&index.hasher.hash_one(key));
+        FindBy { items, seqs: seqs.map(|s| s.iter()), index, key }
+    }
+
+    fn find(&self, id: ItemId) -> Option<*mut Node<T>> {
+        if id.collection != self.collection.uid {
+            return None;
+        }
+        let items = self.items();
+        items.keys.get(&id.seq).and_then(|key| items.map.get(key)).cloned()
+    }
+
+    fn items(&self) -> &Items<T> {
+        // The current thread holds the read lock until the guard is dropped
+        unsafe { &*self.collection.items.get() }
+    }
+}
+
+impl<'c, T> Drop for ExternalSetReadGuard<'c, T> {
+    fn drop(&mut self) {
+        self.collection.unlock_reader();
+    }
+}
+
+/// An opaque identifier for an item in an ExternalSet. It identifies the collection as well as
+/// the item, so IDs from different collections never compare equal.
+///
+/// IDs are assigned in increasing order and are never reused by the same collection, even
+/// though the memory of removed items is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ItemId {
+    /// The `uid` of the collection.
+    collection: u64,
+    seq: u64,
+}
+
+impl fmt::Display for ItemId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.seq)
+    }
+}
+
+/// Implemented by the owner types, so that methods such as `ExternalSetReadGuard::others`
+/// accept any of them.
+pub trait Owner<T> {
+    /// The ID of the owned item.
+    fn id(&self) -> ItemId;
+}
+
+/// A reference to an item that does not keep it in the collection.
+///
+/// Obtained from an owner's `item_ref()`, and resolved against a read guard with
+/// `ExternalSetReadGuard::resolve` to find out whether the item is still in the collection.
+pub struct ItemRef<T> {
+    id: ItemId,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> ItemRef<T> {
+    /// The ID of the item referred to.
+    pub fn id(&self) -> ItemId {
+        self.id
+    }
+}
+
+impl<T> Clone for ItemRef<T> {
+    fn clone(&self) -> ItemRef<T> {
+        *self
+    }
+}
+
+impl<T> Copy for ItemRef<T> {}
+
+impl<T> PartialEq for ItemRef<T> {
+    fn eq(&self, other: &ItemRef<T>) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<T> Eq for ItemRef<T> {}
+
+impl<T> Hash for ItemRef<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl<T> fmt::Debug for ItemRef<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ItemRef").field("id", &self.id).finish()
+    }
+}
+
+/// A secondary index on an ExternalSet, created by `ExternalSet::add_index`, used to find items
+/// by key with `ExternalSetReadGuard::find_by`.
+pub struct Index<T, K> {
+    collection: u64,
+    number: usize,
+    extract: Arc<dyn Fn(&T) -> K + Send + Sync>,
+    hasher: RandomState,
+}
+
+impl<T, K> Clone for Index<T, K> {
+    fn clone(&self) -> Index<T, K> {
+        Index {
+            collection: self.collection,
+            number: self.number,
+            extract: self.extract.clone(),
+            hasher: self.hasher.clone(),
+        }
+    }
+}
+
+/// Iterator over the items in a ExternalSet with a key, returned by `find_by`.
+pub struct FindBy<'a, T: 'a, K: 'a, Q: 'a + ?Sized = K> {
+    items: &'a Items<T>,
+    seqs: Option<::std::collections::btree_set::Iter<'a, u64>>,
+    index: &'a Index<T, K>,
+    key: &'a Q,
+}
+
+impl<'a, T, K: Borrow<Q>, Q: Eq + ?Sized> Iterator for FindBy<'a, T, K, Q> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let (items, index, key) = (self.items, self.index, self.key);
+        self.seqs.as_mut()?
+            .filter_map(|&seq| items.get(seq))
+            .map(|ptr| unsafe { &(*ptr).value })
+            .find(|item| (index.extract)(item).borrow() == key) // skip hash collisions
+    }
+}
+
+/// Iterator over the IDs and items in a ExternalSet.
+pub struct ExternalSetIdIter<'g, T: 'g> {
+    iter: ::std::collections::btree_map::Iter<'g, Key, *mut Node<T>>,
+    collection: u64,
+}
+
+impl<'g, T> Iterator for ExternalSetIdIter<'g, T> {
+    type Item = (ItemId, &'g T);
+
+    fn next(&mut self) -> Option<(ItemId, &'g T)> {
+        let collection = self.collection;
+        self.iter.next()
+            .map(|(key, &ptr)| (ItemId { collection, seq: key.seq }, unsafe { &(*ptr).value }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'g, T> ExactSizeIterator for ExternalSetIdIter<'g, T> {}
+
+/// Iterator over the items in a ExternalSet.
+pub struct ExternalSetIter<'g, T: 'g> {
+    iter: ::std::collections::btree_map::Values<'g, Key, *mut Node<T>>,
+    except: Option<*mut Node<T>>,
+    remaining: usize,
+}
+
+impl<'g, T> Iterator for ExternalSetIter<'g, T> {
+    type Item = &'g T;
+
+    fn next(&mut self) -> Option<&'g T> {
+        if let Some(&i) = self.iter.next() {
+            if Some(i) == self.except { return self.next(); } // skip excluded item
+            self.remaining -= 1;
+            Some(unsafe { &(*i).value })
+        } else {
+            None
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'g, T> ExactSizeIterator for ExternalSetIter<'g, T> {}
+
+/// The key and node of an owned item, and the logic shared by the owner types. Each owner
+/// passes in its collection, or None if it held a `Weak` reference and the collection is gone.
+struct Owned<T> {
+    key: Key,
+    ptr: *mut Node<T>,
+    _marker: PhantomData<T>,
+}
+
+impl<T> Owned<T> {
+    fn value(&self) -> &T {
+        unsafe { &(*self.ptr).value }
+    }
+
+    fn is_revoked(&self) -> bool {
+        unsafe { (*self.ptr).revoked.load(Ordering::SeqCst) }
+    }
+
+    fn id(&self) -> ItemId {
+        ItemId { collection: unsafe { (*self.ptr).collection }, seq: self.key.seq }
+    }
+
+    fn item_ref(&self) -> ItemRef<T> {
+        ItemRef { id: self.id(), _marker: PhantomData }
+    }
+
+    fn priority(&self) -> i32 {
+        self.key.priority.0
+    }
+
+    fn set_priority(&mut self, collection: Option<&ExternalSet<T>>, priority: i32) {
+        self.key = match collection {
+            Some(collection) => collection.reprioritize(self.key, priority),
+            None => Key { priority: Reverse(priority), seq: self.key.seq },
+        };
+    }
+
+    fn update<R, F: FnOnce(&mut T) -> R>(&mut self, collection: Option<&ExternalSet<T>>, f: F)
+        -> R
+    {
+        match collection {
+            Some(collection) => collection.modify("update()", self.key, self.ptr, f),
+            None => f(unsafe { &mut (*self.ptr).value }),
+        }
+    }
+
+    /// Remove the item from the collection and return it, once no hooks are being called for it
+    /// on other threads. The caller must forget the owner.
+    ///
+    /// Hooks are only called while the collection is alive, so there is nothing to wait for
+    /// once it has been dropped.
+    fn take(&self, collection: Option<&ExternalSet<T>>) -> T {
+        if let Some(collection) = collection {
+            collection.detach(self.key);
+            unsafe { collection.wait_unpinned(self.ptr) };
+        }
+        unsafe { Box::from_raw(self.ptr) }.value
+    }
+
+    /// Remove the item from the collection and drop the owner's reference, when the owner is
+    /// dropped.
+    fn release(&self, collection: Option<&ExternalSet<T>>) {
+        match collection {
+            Some(collection) => collection.release(self.key, self.ptr),
+            None => { unsafe { unpin(self.ptr) }; }
+        }
+    }
+}
+
+/// The reference to the collection held by an owner.
+trait CollectionRef<T> {
+    /// Call `f` with the collection, or None if it has been dropped.
+    fn with<R, F: FnOnce(Option<&ExternalSet<T>>) -> R>(&self, f: F) -> R;
+}
+
+impl<T> CollectionRef<T> for &ExternalSet<T> {
+    fn with<R, F: FnOnce(Option<&ExternalSet<T>>) -> R>(&self, f: F) -> R {
+        f(Some(self))
+    }
+}
+
+impl<T> CollectionRef<T> for Arc<ExternalSet<T>> {
+    fn with<R, F: FnOnce(Option<&ExternalSet<T>>) -> R>(&self, f: F) -> R {
+        f(Some(self))
+    }
+}
+
+impl<T> CollectionRef<T> for Weak<ExternalSet<T>> {
+    fn with<R, F: FnOnce(Option<&ExternalSet<T>>) -> R>(&self, f: F) -> R {
+        match self.upgrade() {
+            Some(collection) => f(Some(&collection)),
+            None => f(None),
+        }
+    }
+}
+
+/// The methods common to ItemOwner, ArcItemOwner and WeakItemOwner, which delegate to their
+/// `Owned` core with their `collection`.
+macro_rules! owner_methods {
+    () => {
+        /// Remove the item from the collection, if it is still in it, and return it.
+        ///
+        /// Panics if the current thread holds a read guard on the collection, since the guard
+        /// may still be referencing the item. Dropping the owner instead defers the removal.
+        pub fn take(self) -> T {
+            let value = self.collection.with(|collection| self.item.take(collection));
+            // Release the reference to the collection, but skip `Drop`, which would free the item
+            let mut this = mem::ManuallyDrop::new(self);
+            unsafe { ::std::ptr::drop_in_place(&mut this.collection) };
+            value
+        }
+
+        /// Returns true if the collection has been closed, and the owner should wind down. A
+        /// collection that has been dropped counts as closed.
+        pub fn is_closed(&self) -> bool {
+            self.collection.with(|collection| collection.map_or(true, ExternalSet::is_closed))
+        }
+
+        /// Returns true if the item was evicted from the collection by `ExternalSet::evict` or
+        /// `ExternalSet::retain`. The owner still owns the item, but it is no longer in the
+        /// collection.
+        pub fn is_revoked(&self) -> bool {
+            self.item.is_revoked()
+        }
+
+        /// The ID of the item, which identifies it within its collection.
+        pub fn id(&self) -> ItemId {
+            self.item.id()
+        }
+
+        /// Get a reference to the item that can be used to check whether it is still in the
+        /// collection without holding the owner.
+        pub fn item_ref(&self) -> ItemRef<T> {
+            self.item.item_ref()
+        }
+
+        /// The priority of the item, which determines its position in the iteration order.
+        pub fn priority(&self) -> i32 {
+            self.item.priority()
+        }
+
+        /// Change the priority of the item without removing it from the collection. The item
+        /// keeps its position relative to other items of the same priority inserted before and
+        /// after it.
+        pub fn set_priority(&mut self, priority: i32) {
+            let item = &mut self.item;
+            self.collection.with(|collection| item.set_priority(collection, priority))
+        }
+
+        /// Replace the item with `item`, returning the old value. The item keeps its ID and its
+        /// position in the iteration order, and read guards see either the old or the new
+        /// value.
+        ///
+        /// Panics if the current thread holds a read guard on the collection.
+        pub fn replace(&mut self, item: T) -> T {
+            self.update(|value| mem::replace(value, item))
+        }
+
+        /// Modify the item in place under the collection's write lock, so that read guards see
+        /// it either before or after the change. The item keeps its ID and position.
+        ///
+        /// Panics if the current thread holds a read guard on the collection.
+        pub fn update<R, F: FnOnce(&mut T) -> R>(&mut self, f: F) -> R {
+            let item = &mut self.item;
+            self.collection.with(|collection| item.update(collection, f))
+        }
+    }
+}
+
+/// The owner of an item in a ExternalSet
+pub struct ItemOwner<'s, T: 's> {
+    collection: &'s ExternalSet<T>,
+    item: Owned<T>,
+}
+
+impl<'s, T> ItemOwner<'s, T> {
+    owner_methods!();
+}
+
+impl<'s, T> Owner<T> for ItemOwner<'s, T> {
+    fn id(&self) -> ItemId {
+        self.item.id()
+    }
+}
+
+unsafe impl<'s, T: Send + Sync> Send for ItemOwner<'s, T> {}
+unsafe impl<'s, T: Sync> Sync for ItemOwner<'s, T> {}
+
+impl<'s, T> Drop for ItemOwner<'s, T> {
+    fn drop(&mut self) {
+        self.item.release(Some(self.collection));
+    }
+}
+
+impl <'s, T> Deref for ItemOwner<'s, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.item.value()
+    }
+}
+
+/// The owner of an item in an `Arc`-shared ExternalSet.
+///
+/// Behaves like an ItemOwner, but holds a strong reference to the collection instead of
+/// borrowing it.
+pub struct ArcItemOwner<T> {
+    collection: Arc<ExternalSet<T>>,
+    item: Owned<T>,
+}
+
+impl<T> ArcItemOwner<T> {
+    owner_methods!();
+
+    /// The collection this item belongs to.
+    pub fn collection(&self) -> &Arc<ExternalSet<T>> {
+        &self.collection
+    }
+}
+
+impl<T> Owner<T> for ArcItemOwner<T> {
+    fn id(&self) -> ItemId {
+        self.item.id()
+    }
+}
+
+unsafe impl<T: Send + Sync> Send for ArcItemOwner<T> {}
+unsafe impl<T: Sync> Sync for ArcItemOwner<T> {}
 
-impl<'c, T> ExternalSetReadGuard<'c, T> {
-    /// Iterate over references to items in the ExternalSet.
-    ///
-    /// The order of iteration is unspecified.
-    pub fn iter<'g: 'c>(&'g self) -> ExternalSetIter<'g, T> {
-        ExternalSetIter { iter: self.0.iter(), except: None, }
+impl<T> Drop for ArcItemOwner<T> {
+    fn drop(&mut self) {
+        self.item.release(Some(&self.collection));
     }
+}
 
-    /// Iterate over references to items in the ExternalSet, excluding the one owned by the
-    /// provided ItemOwner.
-    ///
-    /// If the provided ItemOwner is not in the collection (e.g. it was inserted into another
-    /// collection), all items will be yielded by the iterator.
-    ///
-    /// The order of iteration is unspecified.
-    pub fn others<'g: 'c>(&'g self, except: &ItemOwner<T>) -> ExternalSetIter<'g, T> {
-        ExternalSetIter { iter: self.0.iter(), except: Some(except.ptr) }
+impl<T> Deref for ArcItemOwner<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.item.value()
     }
 }
 
-/// Iterator over the items in a ExternalSet.
-pub struct ExternalSetIter<'g, T: 'g> {
-    iter: ::std::collections::hash_set::Iter<'g, *mut T>,
-    except: Option<*mut T>
+/// The owner of an item in an `Arc`-shared ExternalSet that does not keep the collection alive.
+pub struct WeakItemOwner<T> {
+    collection: Weak<ExternalSet<T>>,
+    item: Owned<T>,
 }
 
-impl<'g, T> Iterator for ExternalSetIter<'g, T> {
-    type Item = &'g T;
+impl<T> WeakItemOwner<T> {
+    owner_methods!();
 
-    fn next(&mut self) -> Option<&'g T> {
-        if let Some(&i) = self.iter.next() {
-            if Some(i) == self.except { return self.next(); } // skip excluded item
-            Some(unsafe { &*i })
-        } else {
-            None
-        }
+    /// Returns true if the collection has not yet been dropped.
+    pub fn is_set_alive(&self) -> bool {
+        self.collection.strong_count() > 0
     }
-}
 
-/// The owner of an item in a ExternalSet
-pub struct ItemOwner<'s, T: 's> {
-    collection: &'s ExternalSet<T>,
-    ptr: *mut T,
-    _marker: PhantomData<T>,
+    /// Get a strong reference to the collection, if it still exists.
+    pub fn collection(&self) -> Option<Arc<ExternalSet<T>>> {
+        self.collection.upgrade()
+    }
 }
 
-impl<'s, T> ItemOwner<'s, T> {
-    /// Remove the item from the collection and return it.
-    pub fn take(self) -> T {
-        unsafe {
-            self.collection.items.write().unwrap().remove(&self.ptr);
-            let ret = *Box::from_raw(self.ptr);
-            ::std::mem::forget(self);
-            ret
-        }
+impl<T> Owner<T> for WeakItemOwner<T> {
+    fn id(&self) -> ItemId {
+        self.item.id()
     }
 }
 
-unsafe impl<'s, T: Send + Sync> Send for ItemOwner<'s, T> {}
-unsafe impl<'s, T: Sync> Sync for ItemOwner<'s, T> {}
+unsafe impl<T: Send + Sync> Send for WeakItemOwner<T> {}
+unsafe impl<T: Sync> Sync for WeakItemOwner<T> {}
 
-impl<'s, T> Drop for ItemOwner<'s, T> {
+impl<T> Drop for WeakItemOwner<T> {
     fn drop(&mut self) {
-        self.collection.items.write().unwrap().remove(&self.ptr);
-        drop(unsafe { *Box::from_raw(self.ptr) });
+        let item = &self.item;
+        self.collection.with(|collection| item.release(collection));
     }
 }
 
-impl <'s, T> Deref for ItemOwner<'s, T> {
+impl<T> Deref for WeakItemOwner<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.item.value()
+    }
+}
+
+/// Either a borrowed or an `Arc`-based owner, for types built on ExternalSet that offer both,
+/// such as the receivers of a ChannelHub.
+enum EitherOwner<'s, T: 's> {
+    Borrowed(ItemOwner<'s, T>),
+    Arc(ArcItemOwner<T>),
+}
+
+impl<'s, T> Deref for EitherOwner<'s, T> {
     type Target = T;
     fn deref(&self) -> &T {
-        unsafe { &*self.ptr }
+        match *self {
+            EitherOwner::Borrowed(ref owner) => owner,
+            EitherOwner::Arc(ref owner) => owner,
+        }
     }
 }
 
 #[test]
+#[allow(clippy::map_clone)]
 fn test1() {
     let c = ExternalSet::<u32>::new();
     let i1 = c.insert(1);
@@ -142,3 +1704,742 @@ fn test1() {
 
     assert_eq!(c.lock().iter().count(), 0);
 }
+
+#[test]
+fn test_arc() {
+    let c = Arc::new(ExternalSet::<u32>::new());
+    let i1 = c.insert_arc(1);
+    let i2 = c.insert_arc(2);
+
+    let t = ::std::thread::spawn(move || {
+        assert_eq!(*i1, 1);
+        assert_eq!(i1.collection().lock().iter().count(), 2);
+    });
+    t.join().unwrap();
+
+    assert_eq!(c.lock().iter().cloned().collect::<Vec<_>>(), vec![2]);
+    let i3 = c.insert_arc(3);
+    assert!(c.lock().contains(&i2));
+    assert_eq!(c.lock().others(&i2).cloned().collect::<Vec<_>>(), vec![3]);
+    drop(i3);
+    assert_eq!(i2.take(), 2);
+    assert_eq!(c.lock().iter().count(), 0);
+    assert_eq!(Arc::strong_count(&c), 1);
+}
+
+#[test]
+fn test_weak() {
+    let c = Arc::new(ExternalSet::<u32>::new());
+    let i1 = c.insert_weak(1);
+    let i2 = c.insert_weak(2);
+    assert!(i1.is_set_alive());
+    assert_eq!(c.lock().iter().count(), 2);
+
+    drop(i1);
+    assert_eq!(c.lock().iter().cloned().collect::<Vec<_>>(), vec![2]);
+    assert!(c.lock().contains(&i2));
+    assert_eq!(c.lock().others(&i2).count(), 0);
+
+    drop(c);
+    assert!(!i2.is_set_alive());
+    assert!(i2.collection().is_none());
+    assert_eq!(*i2, 2);
+    assert_eq!(i2.take(), 2);
+}
+
+#[test]
+fn test_insertion_order() {
+    let c = ExternalSet::<u32>::new();
+    let owners = (0..20).map(|i| c.insert(i)).collect::<Vec<_>>();
+    assert_eq!(c.lock().iter().cloned().collect::<Vec<_>>(), (0..20).collect::<Vec<_>>());
+
+    let mut owners = owners.into_iter().filter(|i| **i % 3 != 0).collect::<Vec<_>>();
+    owners.push(c.insert(100));
+    assert_eq!(c.lock().others(&owners[0]).cloned().collect::<Vec<_>>(),
+               vec![2, 4, 5, 7, 8, 10, 11, 13, 14, 16, 17, 19, 100]);
+}
+
+#[test]
+fn test_priority() {
+    let c = ExternalSet::<&str>::new();
+    let _a = c.insert("a");
+    let mut b = c.insert_with_priority("b", -1);
+    let _c = c.insert_with_priority("c", 10);
+    let _d = c.insert("d");
+    let _e = c.insert_with_priority("e", 10);
+    assert_eq!(c.lock().iter().cloned().collect::<Vec<_>>(), vec!["c", "e", "a", "d", "b"]);
+
+    assert_eq!(b.priority(), -1);
+    b.set_priority(10);
+    assert_eq!(b.priority(), 10);
+    assert_eq!(c.lock().iter().cloned().collect::<Vec<_>>(), vec!["b", "c", "e", "a", "d"]);
+    drop(b);
+    assert_eq!(c.lock().iter().cloned().collect::<Vec<_>>(), vec!["c", "e", "a", "d"]);
+}
+
+#[test]
+fn test_drop_while_reading() {
+    use std::cell::RefCell;
+
+    let c = ExternalSet::<u32>::new();
+    let owners = RefCell::new((0..5).map(|i| c.insert(i)).collect::<Vec<_>>());
+
+    {
+        let guard = c.lock();
+        let mut seen = vec![];
+        for &i in guard.iter() {
+            seen.push(i);
+            if i == 1 {
+                // Unsubscribe self and a later item while iterating
+                owners.borrow_mut().retain(|o| **o != 1 && **o != 3);
+                assert_eq!(c.lock().iter().count(), 5);
+            }
+        }
+        assert_eq!(seen, vec![0, 1, 2, 3, 4]);
+
+        let mut o = owners.borrow_mut().pop().unwrap();
+        o.set_priority(1);
+        owners.borrow_mut().insert(0, o);
+    }
+
+    assert_eq!(c.lock().iter().cloned().collect::<Vec<_>>(), vec![4, 0, 2]);
+    drop(owners);
+    assert_eq!(c.lock().iter().count(), 0);
+}
+
+#[test]
+fn test_nested_lock() {
+    let c = Arc::new(ExternalSet::new());
+    let _a = c.insert_arc(1);
+    let outer = c.lock();
+
+    // Another thread waits for the write lock, which must not block a nested read guard
+    let c2 = c.clone();
+    let t = thread::spawn(move || c2.insert_arc(2).take());
+    thread::sleep(Duration::from_millis(50));
+    let inner = c.lock();
+    drop(outer);
+    assert_eq!(inner.iter().cloned().collect::<Vec<_>>(), vec![1]);
+    drop(inner);
+    assert_eq!(t.join().unwrap(), 2);
+}
+
+#[test]
+#[should_panic(expected = "read guard")]
+fn test_take_while_reading() {
+    let c = ExternalSet::<u32>::new();
+    let i1 = c.insert(1);
+    let _guard = c.lock();
+    i1.take();
+}
+
+#[test]
+fn test_insert_while_reading() {
+    let c = ExternalSet::<u32>::new();
+    let _i1 = c.insert(1);
+    let mut added = vec![];
+
+    {
+        let guard = c.lock();
+        for &i in guard.iter() {
+            added.push(c.insert(i + 10));
+            added.push(c.insert_with_priority(i + 20, 1));
+        }
+        assert_eq!(guard.iter().cloned().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(*added[0], 11);
+
+        // An item inserted and removed before the guard is released never becomes visible
+        drop(added.pop());
+    }
+
+    assert_eq!(c.lock().iter().cloned().collect::<Vec<_>>(), vec![1, 11]);
+    drop(added);
+    assert_eq!(c.lock().iter().cloned().collect::<Vec<_>>(), vec![1]);
+}
+
+#[cfg(test)]
+fn poison_set<T>(c: &ExternalSet<T>) {
+    let _ = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+        let _ = c.write(PoisonPolicy::Recover, |_, _| panic!("poison"));
+    }));
+}
+
+#[test]
+fn test_poison_propagate() {
+    let c = ExternalSet::<u32>::new();
+    let i1 = c.insert(1);
+    poison_set(&c);
+
+    assert_eq!(c.try_insert(2).err(), Some(Error::Poisoned));
+    assert!(c.try_lock().is_err());
+    drop(i1);
+}
+
+#[test]
+fn test_poison_recover() {
+    let c = ExternalSet::<u32>::with_poison_policy(PoisonPolicy::Recover);
+    let i1 = c.insert(1);
+    poison_set(&c);
+
+    let i2 = c.try_insert(2).unwrap();
+    assert_eq!(c.lock().iter().cloned().collect::<Vec<_>>(), vec![1, 2]);
+    assert_eq!(i1.take(), 1);
+    drop(i2);
+    assert_eq!(c.try_lock().unwrap().iter().count(), 0);
+}
+
+#[test]
+fn test_hooks() {
+    let c = Arc::new(ExternalSet::<u32>::new());
+    let log = Arc::new(Mutex::new(Vec::new()));
+
+    let (l, c2) = (log.clone(), Arc::downgrade(&c));
+    c.on_insert(move |&i| {
+        // Hooks run outside the lock, so they may use the collection
+        let count = c2.upgrade().unwrap().lock().iter().count();
+        l.lock().unwrap().push(("insert", i, count));
+    });
+    let (l, c2) = (log.clone(), Arc::downgrade(&c));
+    c.on_remove(move |&i| {
+        let count = c2.upgrade().unwrap().lock().iter().count();
+        l.lock().unwrap().push(("remove", i, count));
+    });
+
+    let i1 = c.insert_arc(1);
+    let i2 = c.insert_arc(2);
+    assert_eq!(i1.take(), 1);
+
+    let i3 = {
+        let guard = c.lock();
+        drop(i2);
+        let i3 = c.insert_arc(3);
+        assert_eq!(guard.iter().count(), 1);
+        assert_eq!(log.lock().unwrap().len(), 3);
+        i3
+    };
+    drop(i3);
+
+    assert_eq!(*log.lock().unwrap(), vec![
+        ("insert", 1, 1),
+        ("insert", 2, 2),
+        ("remove", 1, 1),
+        ("remove", 2, 1),
+        ("insert", 3, 1),
+        ("remove", 3, 0),
+    ]);
+}
+
+#[test]
+fn test_wait_empty() {
+    let c = Arc::new(ExternalSet::<u32>::new());
+    c.wait_empty();
+
+    let i1 = c.insert_arc(1);
+    let i2 = c.insert_arc(2);
+    assert!(!c.wait_empty_timeout(Duration::from_millis(10)));
+
+    let t = thread::spawn(move || {
+        drop(i1);
+        thread::sleep(Duration::from_millis(10));
+        drop(i2);
+    });
+    c.wait_empty();
+    assert_eq!(c.lock().iter().count(), 0);
+    assert!(c.wait_empty_timeout(Duration::from_millis(0)));
+    t.join().unwrap();
+}
+
+#[cfg(all(test, feature = "async"))]
+fn block_on<F: ::std::future::Future>(future: F) -> F::Output {
+    use std::task::{Context, Poll, Wake, Waker};
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_wait_empty_async() {
+    let c = Arc::new(ExternalSet::<u32>::new());
+    block_on(c.wait_empty_async());
+
+    let i1 = c.insert_arc(1);
+    let t = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        drop(i1);
+    });
+    block_on(c.wait_empty_async());
+    assert_eq!(c.lock().iter().count(), 0);
+    t.join().unwrap();
+}
+
+#[test]
+fn test_wait_until() {
+    let c = Arc::new(ExternalSet::<u32>::new());
+    let _i1 = c.insert_arc(1);
+    assert!(!c.wait_until_timeout(Duration::from_millis(10), |g| g.iter().count() >= 3));
+
+    let c2 = c.clone();
+    let t = thread::spawn(move || {
+        (2..6).map(|i| {
+            thread::sleep(Duration::from_millis(5));
+            c2.insert_arc(i)
+        }).collect::<Vec<_>>()
+    });
+    c.wait_until(|g| g.iter().count() >= 3);
+    assert!(c.wait_until_timeout(Duration::from_secs(10), |g| g.iter().any(|&i| i == 5)));
+    let owners = t.join().unwrap();
+    assert_eq!(owners.len(), 4);
+}
+
+#[test]
+fn test_close() {
+    let c = ExternalSet::<u32>::new();
+    let closing = Arc::new(Mutex::new(Vec::new()));
+    let l = closing.clone();
+    c.on_close(move |&i| l.lock().unwrap().push(i));
+
+    let i1 = c.insert(1);
+    let i2 = c.insert(2);
+    assert!(!i1.is_closed());
+
+    c.close();
+    c.close();
+    assert!(c.is_closed());
+    assert!(i1.is_closed());
+    assert_eq!(*closing.lock().unwrap(), vec![1, 2]);
+    assert_eq!(c.try_insert(3).err(), Some(Error::Closed));
+    assert_eq!(c.lock().iter().count(), 2);
+
+    drop(i1);
+    assert_eq!(i2.take(), 2);
+    assert_eq!(c.lock().iter().count(), 0);
+
+    let c = Arc::new(ExternalSet::<u32>::new());
+    let i1 = c.insert_weak(1);
+    assert!(!i1.is_closed());
+    c.close();
+    assert_eq!(c.try_insert_arc(2).err(), Some(Error::Closed));
+    assert_eq!(c.try_insert_weak(3).err(), Some(Error::Closed));
+    drop(c);
+    assert!(i1.is_closed());
+}
+
+#[test]
+fn test_close_race() {
+    let c = Arc::new(ExternalSet::<u32>::new());
+    let closing = Arc::new(Mutex::new(Vec::new()));
+    let l = closing.clone();
+    c.on_close(move |&i| l.lock().unwrap().push(i));
+
+    // Every insert that succeeds, however it races with `close()`, is seen by the hook
+    let threads = (0..4).map(|t| {
+        let c = c.clone();
+        thread::spawn(move || {
+            (0..).map(|i| c.try_insert_arc(t * 1_000_000 + i))
+                .take_while(Result::is_ok)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        })
+    }).collect::<Vec<_>>();
+    thread::sleep(Duration::from_millis(10));
+    c.close();
+
+    let closing = closing.lock().unwrap().drain(..).collect::<::std::collections::HashSet<_>>();
+    for t in threads {
+        for owner in t.join().unwrap() {
+            assert!(closing.contains(&*owner));
+        }
+    }
+}
+
+#[test]
+fn test_evict() {
+    let c = ExternalSet::<u32>::new();
+    let removed = Arc::new(Mutex::new(Vec::new()));
+    let r = removed.clone();
+    c.on_remove(move |&i| r.lock().unwrap().push(i));
+
+    let owners = (0..6).map(|i| c.insert(i)).collect::<Vec<_>>();
+    c.evict(owners[1].id());
+    assert!(owners[1].is_revoked());
+    assert!(!owners[0].is_revoked());
+    assert_eq!(*owners[1], 1);
+
+    // A panic in `keep` evicts nothing and leaves the collection usable
+    let result = panic::catch_unwind(AssertUnwindSafe(|| c.retain(|_| panic!("keep failed"))));
+    assert!(result.is_err());
+    assert_eq!(c.lock().iter().count(), 5);
+
+    assert_eq!(c.retain(|&i| i % 2 == 0), 2);
+    assert!(owners[3].is_revoked() && owners[5].is_revoked());
+    assert_eq!(c.lock().iter().cloned().collect::<Vec<_>>(), vec![0, 2, 4]);
+
+    {
+        let guard = c.lock();
+        for &i in guard.iter() {
+            if i == 2 {
+                c.evict(owners[4].id());
+            }
+        }
+        assert!(!owners[4].is_revoked());
+    }
+    assert!(owners[4].is_revoked());
+    assert_eq!(c.lock().iter().cloned().collect::<Vec<_>>(), vec![0, 2]);
+
+    // Dropping or taking revoked owners does not report them as removed again
+    let mut owners = owners;
+    assert_eq!(owners.remove(5).take(), 5);
+    drop(owners);
+    assert_eq!(*removed.lock().unwrap(), vec![1, 3, 5, 4, 0, 2]);
+    assert_eq!(c.lock().iter().count(), 0);
+}
+
+#[test]
+fn test_ids() {
+    let c = ExternalSet::<u32>::new();
+    let i1 = c.insert(1);
+    let i2 = c.insert_with_priority(2, 1);
+    assert!(i1.id() != i2.id());
+
+    let guard = c.lock();
+    assert_eq!(guard.iter_with_ids().collect::<Vec<_>>(), vec![(i2.id(), &2), (i1.id(), &1)]);
+    assert_eq!(guard.get(i1.id()), Some(&1));
+    assert_eq!(guard.get(i2.id()), Some(&2));
+    drop(guard);
+
+    let id = i1.id();
+    drop(i1);
+    let i3 = c.insert(1);
+    assert!(i3.id() != id);
+    assert_eq!(c.lock().get(id), None);
+    assert_eq!(c.lock().get(i3.id()), Some(&1));
+
+    // IDs from another collection never match, even with the same sequence number
+    let c2 = ExternalSet::<u32>::new();
+    let others = (0..4).map(|i| c2.insert(i)).collect::<Vec<_>>();
+    for other in &others {
+        assert!(other.id() != i2.id() && other.id() != i3.id());
+        assert_eq!(c.lock().get(other.id()), None);
+        c.evict(other.id());
+    }
+    assert_eq!(c.lock().iter().count(), 2);
+    c2.evict(i2.id());
+    assert_eq!(c2.lock().iter().count(), 4);
+}
+
+#[test]
+fn test_item_ref() {
+    let c = ExternalSet::<u32>::new();
+    let c2 = ExternalSet::<u32>::new();
+    let i1 = c.insert(1);
+    let i2 = c.insert(2);
+    let _other = c2.insert(3);
+
+    let r1 = i1.item_ref();
+    let r2 = i2.item_ref();
+    assert_eq!(r1, r1.clone());
+    assert!(r1 != r2);
+    assert_eq!(c.lock().resolve(&r1), Some(&1));
+    assert_eq!(c2.lock().resolve(&r1), None);
+
+    drop(i1);
+    assert_eq!(c.lock().resolve(&r1), None);
+    assert_eq!(c.lock().resolve(&r2), Some(&2));
+    c.evict(r2.id());
+    assert_eq!(c.lock().resolve(&r2), None);
+}
+
+#[test]
+fn test_len() {
+    let c = ExternalSet::<u32>::new();
+    let c2 = ExternalSet::<u32>::new();
+    assert!(c.is_empty());
+    let i1 = c.insert(1);
+    let i2 = c.insert(2);
+    let other = c2.insert(3);
+    assert_eq!(c.len(), 2);
+
+    {
+        let guard = c.lock();
+        assert_eq!(guard.len(), 2);
+        assert!(!guard.is_empty());
+        assert!(guard.contains(&i1));
+        assert!(!guard.contains(&other));
+
+        let mut iter = guard.iter();
+        assert_eq!(iter.len(), 2);
+        iter.next();
+        assert_eq!(iter.len(), 1);
+        assert_eq!(guard.others(&i1).len(), 1);
+        assert_eq!(guard.others(&other).len(), 2);
+        assert_eq!(guard.iter_with_ids().len(), 2);
+
+        drop(i2);
+        assert_eq!(guard.len(), 2);
+    }
+    assert_eq!(c.len(), 1);
+
+    c.evict(i1.id());
+    assert!(!c.lock().contains(&i1));
+    assert!(c.is_empty());
+    assert!(c.lock().is_empty());
+}
+
+#[test]
+fn test_index() {
+    #[derive(Debug, PartialEq)]
+    struct Client { user: u32, name: &'static str }
+
+    let c = ExternalSet::<Client>::new();
+    let _a = c.insert(Client { user: 1, name: "a" });
+    let by_user = c.add_index(|client| client.user);
+    let by_name = c.add_index(|client| client.name);
+    let b = c.insert(Client { user: 2, name: "b" });
+    let _c = c.insert(Client { user: 1, name: "c" });
+
+    {
+        let guard = c.lock();
+        let names = |user| guard.find_by(&by_user, &user).map(|c| c.name).collect::<Vec<_>>();
+        assert_eq!(names(1), vec!["a", "c"]);
+        assert_eq!(names(2), vec!["b"]);
+        assert_eq!(names(3), Vec::<&str>::new());
+        assert_eq!(guard.find_by(&by_name, &"c").next(), Some(&Client { user: 1, name: "c" }));
+    }
+
+    assert_eq!(b.take().name, "b");
+    assert_eq!(c.lock().find_by(&by_user, &2).count(), 0);
+    c.retain(|client| client.name != "a");
+    assert_eq!(c.lock().find_by(&by_user, &1).map(|c| c.name).collect::<Vec<_>>(), vec!["c"]);
+}
+
+#[test]
+fn test_insert_unique() {
+    let c = ExternalSet::<&str>::new();
+    let _a = c.insert("a");
+    assert!(c.lock().contains_value(&"a"));
+
+    let b = c.insert_unique("b").unwrap();
+    assert_eq!(c.insert_unique("a").err().map(DuplicateError::into_inner), Some("a"));
+    assert_eq!(c.insert_unique("b").err(), Some(DuplicateError("b")));
+    {
+        let guard = c.lock();
+        assert!(guard.contains_value(&"b"));
+        assert!(!guard.contains_value(&"c"));
+        assert_eq!(guard.len(), 2);
+    }
+
+    drop(b);
+    assert!(!c.lock().contains_value(&"b"));
+    let _b = c.insert_unique("b").unwrap();
+    assert_eq!(c.lock().iter().cloned().collect::<Vec<_>>(), vec!["a", "b"]);
+
+    c.close();
+    assert_eq!(c.try_insert_unique("c").err(), Some(Error::Closed));
+}
+
+#[test]
+fn test_update() {
+    let c = ExternalSet::<(u32, &str)>::new();
+    let by_id = c.add_index(|item| item.0);
+    let mut a = c.insert((1, "a"));
+    let _b = c.insert((2, "b"));
+    let id = a.id();
+
+    assert_eq!(a.replace((3, "a")), (1, "a"));
+    a.update(|item| item.1 = "c");
+    assert_eq!(*a, (3, "c"));
+    assert_eq!(a.id(), id);
+    {
+        let guard = c.lock();
+        assert_eq!(guard.iter().cloned().collect::<Vec<_>>(), vec![(3, "c"), (2, "b")]);
+        assert_eq!(guard.find_by(&by_id, &1).count(), 0);
+        assert_eq!(guard.find_by(&by_id, &3).next(), Some(&(3, "c")));
+    }
+
+    // A panic in `update` leaves the collection usable and the item indexed
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        a.update(|item| { item.0 = 4; panic!("update failed") })
+    }));
+    assert!(result.is_err());
+    assert_eq!(*a, (4, "c"));
+    assert_eq!(c.lock().find_by(&by_id, &4).next(), Some(&(4, "c")));
+    let _d = c.insert((5, "d"));
+
+    let c = Arc::new(ExternalSet::<u32>::new());
+    let mut w = c.insert_weak(1);
+    assert_eq!(w.update(|i| { *i += 1; *i }), 2);
+    drop(c);
+    assert_eq!(w.replace(5), 2);
+    assert_eq!(*w, 5);
+}
+
+/// An item that records when it is dropped.
+#[cfg(test)]
+struct DropFlag(Arc<AtomicBool>);
+
+#[cfg(test)]
+impl Drop for DropFlag {
+    fn drop(&mut self) { self.0.store(true, Ordering::SeqCst) }
+}
+
+/// The test side of a hook that signals `started` when it is called, then blocks until `go` is
+/// signalled, and records whether the item was still alive.
+#[cfg(test)]
+struct BlockingHook {
+    started: ::std::sync::mpsc::Receiver<()>,
+    go: ::std::sync::mpsc::Sender<()>,
+    seen_alive: Arc<AtomicBool>,
+}
+
+#[cfg(test)]
+impl BlockingHook {
+    /// Returns the test side and the callback to register.
+    fn new() -> (BlockingHook, impl Fn(&DropFlag) + Send + Sync + 'static) {
+        use std::sync::mpsc::channel;
+
+        let (started_tx, started) = channel();
+        let (go, go_rx) = channel();
+        let (started_tx, go_rx) = (Mutex::new(started_tx), Mutex::new(go_rx));
+        let seen_alive = Arc::new(AtomicBool::new(false));
+        let s = seen_alive.clone();
+        let callback = move |item: &DropFlag| {
+            started_tx.lock().unwrap().send(()).unwrap();
+            go_rx.lock().unwrap().recv().unwrap();
+            s.store(!item.0.load(Ordering::SeqCst), Ordering::SeqCst);
+        };
+        (BlockingHook { started, go, seen_alive }, callback)
+    }
+}
+
+#[test]
+fn test_evict_hook_keeps_item_alive() {
+    let c = Arc::new(ExternalSet::new());
+    let (hook, callback) = BlockingHook::new();
+    c.on_remove(callback);
+
+    let dropped = Arc::new(AtomicBool::new(false));
+    let owner = c.insert_arc(DropFlag(dropped.clone()));
+    let (c2, id) = (c.clone(), owner.id());
+    let t = thread::spawn(move || c2.evict(id));
+
+    // Drop the revoked owner while the hook for the eviction is running on the other thread
+    hook.started.recv().unwrap();
+    assert!(owner.is_revoked());
+    drop(owner);
+    assert!(!dropped.load(Ordering::SeqCst));
+    hook.go.send(()).unwrap();
+    t.join().unwrap();
+
+    assert!(hook.seen_alive.load(Ordering::SeqCst));
+    assert!(dropped.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_take_waits_for_hook() {
+    let c = Arc::new(ExternalSet::new());
+    let (hook, callback) = BlockingHook::new();
+    c.on_remove(callback);
+
+    let dropped = Arc::new(AtomicBool::new(false));
+    let owner = c.insert_arc(DropFlag(dropped.clone()));
+    let (c2, id) = (c.clone(), owner.id());
+    let t = thread::spawn(move || c2.evict(id));
+
+    // take() blocks until the hook for the eviction returns on the other thread
+    hook.started.recv().unwrap();
+    let go = hook.go.clone();
+    let release = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        go.send(()).unwrap();
+    });
+    let item = owner.take();
+    assert!(hook.seen_alive.load(Ordering::SeqCst));
+    assert!(!dropped.load(Ordering::SeqCst));
+    drop(item);
+    assert!(dropped.load(Ordering::SeqCst));
+    release.join().unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn test_insert_hook_keeps_item_alive() {
+    use std::sync::mpsc::channel;
+
+    let c = Arc::new(ExternalSet::new());
+    let (hook, callback) = BlockingHook::new();
+    c.on_insert(callback);
+
+    // Defer an insert while another thread also holds a read guard, so that the other thread
+    // applies it and calls the hook when it releases the last guard
+    let guard = c.lock();
+    let dropped = Arc::new(AtomicBool::new(false));
+    let owner = c.insert_arc(DropFlag(dropped.clone()));
+    let (reading_tx, reading_rx) = channel();
+    let (release_tx, release_rx) = channel();
+    let c2 = c.clone();
+    let t = thread::spawn(move || {
+        let guard = c2.lock();
+        reading_tx.send(()).unwrap();
+        release_rx.recv().unwrap();
+        drop(guard);
+    });
+    reading_rx.recv().unwrap();
+    drop(guard);
+    release_tx.send(()).unwrap();
+
+    // Drop the owner while the hook is running on the other thread
+    hook.started.recv().unwrap();
+    drop(owner);
+    assert!(!dropped.load(Ordering::SeqCst));
+    hook.go.send(()).unwrap();
+    t.join().unwrap();
+
+    assert!(hook.seen_alive.load(Ordering::SeqCst));
+    assert!(dropped.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_hook_panic() {
+    let c = Arc::new(ExternalSet::new());
+    let removed = Arc::new(Mutex::new(Vec::new()));
+    let r = removed.clone();
+    c.on_remove(|&v| if v < 3 { panic!("remove {}", v) });
+    c.on_remove(move |&v| r.lock().unwrap().push(v));
+    let a = c.insert_arc(1);
+    let b = c.insert_arc(2);
+    let c2 = c.clone();
+
+    // Every evicted item is still unpinned and reported, and the first panic is resumed
+    let result = thread::spawn(move || c2.retain(|_| false)).join();
+    assert!(result.is_err());
+    assert_eq!(*removed.lock().unwrap(), vec![1, 2]);
+    assert_eq!(b.take(), 2);
+    assert_eq!(a.take(), 1);
+    c.wait_empty();
+
+    // The item is removed again if inserting it panics
+    c.on_insert(|&v| if v == 4 { panic!("insert {}", v) });
+    let c2 = c.clone();
+    assert!(thread::spawn(move || { c2.insert_arc(4); }).join().is_err());
+    assert!(c.lock().iter().next().is_none());
+    let d = c.insert_arc(5);
+    assert_eq!(c.lock().iter().cloned().collect::<Vec<_>>(), vec![5]);
+    drop(d);
+    assert_eq!(*removed.lock().unwrap(), vec![1, 2, 4, 5]);
+}
@@ -0,0 +1,84 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use {ExternalSet, ExternalSetIter, ExternalSetReadGuard, ItemOwner};
+
+/// An ExternalSet whose items are each wrapped in their own Mutex, so that they can be mutated
+/// while the set is only locked for reading.
+///
+/// Several threads can iterate with `iter_mut_locked` at once, each locking one item at a time,
+/// so broadcasters mutating disjoint subscribers do not block each other. Owners access their
+/// item through the same Mutex, e.g. `owner.lock()`.
+pub type LockedSet<T> = ExternalSet<Mutex<T>>;
+
+/// The owner of an item in a LockedSet.
+pub type LockedItemOwner<'s, T> = ItemOwner<'s, Mutex<T>>;
+
+impl<'c, T> ExternalSetReadGuard<'c, Mutex<T>> {
+    /// Iterate over the items, locking each one as it is reached and yielding its MutexGuard.
+    ///
+    /// Drop each MutexGuard before advancing the iterator, so that only one item is locked at a
+    /// time; holding several risks deadlock with other threads iterating in the same order. A
+    /// Mutex poisoned by a panic is recovered, since a panicking handler should not disable the
+    /// subscriber for every later iteration.
+    pub fn iter_mut_locked<'g: 'c>(&'g self) -> LockedIter<'g, T> {
+        LockedIter { iter: self.iter() }
+    }
+}
+
+/// Iterator over the items in a LockedSet, returned by `iter_mut_locked`.
+pub struct LockedIter<'g, T: 'g> {
+    iter: ExternalSetIter<'g, Mutex<T>>,
+}
+
+impl<'g, T> Iterator for LockedIter<'g, T> {
+    type Item = MutexGuard<'g, T>;
+
+    fn next(&mut self) -> Option<MutexGuard<'g, T>> {
+        self.iter.next().map(|item| item.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'g, T> ExactSizeIterator for LockedIter<'g, T> {}
+
+#[test]
+fn test_iter_mut_locked() {
+    use std::sync::Arc;
+    use std::thread;
+
+    let set = Arc::new(LockedSet::<u32>::new());
+    let a = set.insert(Mutex::new(0));
+    let b = set.insert(Mutex::new(10));
+
+    let threads: Vec<_> = (0..4).map(|_| {
+        let set = set.clone();
+        thread::spawn(move || {
+            for _ in 0..100 {
+                for mut count in set.lock().iter_mut_locked() {
+                    *count += 1;
+                }
+            }
+        })
+    }).collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    assert_eq!(*a.lock().unwrap(), 400);
+    assert_eq!(*b.lock().unwrap(), 410);
+
+    // A poisoned item is still yielded
+    let _ = thread::spawn({
+        let set = set.clone();
+        move || {
+            let guard = set.lock();
+            let _count = guard.iter_mut_locked().next();
+            panic!("poison the first item");
+        }
+    }).join();
+    assert!(a.is_poisoned());
+    assert_eq!(set.lock().iter_mut_locked().map(|c| *c).collect::<Vec<_>>(), vec![400, 410]);
+}
@@ -0,0 +1,186 @@
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::ops::Deref;
+
+use {ExternalSet, ExternalSetIter, ExternalSetReadGuard, FindBy, Index, ItemOwner};
+
+/// A thread-safe map from keys to sets of items owned externally by a MapItemOwner, for
+/// example the clients subscribed to each room or topic.
+///
+/// Any number of items can be inserted under the same key. A key is present in the map while at
+/// least one of its items is, and disappears when the owner of its last item is dropped.
+///
+/// Entries are stored in an ExternalSet, indexed by key, so owners can be dropped and items
+/// inserted while the same thread is iterating, with the same deferral rules.
+pub struct ExternalMap<K, V> {
+    entries: ExternalSet<(K, V)>,
+    by_key: Index<(K, V), K>,
+}
+
+impl<K: Hash + Eq + Clone + 'static, V: 'static> ExternalMap<K, V> {
+    /// Create an empty ExternalMap
+    pub fn new() -> ExternalMap<K, V> {
+        let entries = ExternalSet::new();
+        let by_key = entries.add_index(|entry: &(K, V)| entry.0.clone());
+        ExternalMap { entries, by_key }
+    }
+
+    /// Add an item under `key`, returning a MapItemOwner to own it. When the MapItemOwner is
+    /// dropped, the value will be removed from the map.
+    pub fn insert<'m>(&'m self, key: K, value: V) -> MapItemOwner<'m, K, V> {
+        MapItemOwner { owner: self.entries.insert((key, value)) }
+    }
+
+    /// Lock the map for iteration. References obtained from the guard have the lifetime of the
+    /// guard.
+    pub fn lock(&self) -> ExternalMapReadGuard<'_, K, V> {
+        ExternalMapReadGuard { map: self, entries: self.entries.lock() }
+    }
+}
+
+impl<K: Hash + Eq + Clone + 'static, V: 'static> Default for ExternalMap<K, V> {
+    fn default() -> ExternalMap<K, V> {
+        ExternalMap::new()
+    }
+}
+
+/// RAII structure used to look up and iterate over the items in an ExternalMap, and unlock the
+/// map when dropped.
+pub struct ExternalMapReadGuard<'m, K: 'm, V: 'm> {
+    map: &'m ExternalMap<K, V>,
+    entries: ExternalSetReadGuard<'m, (K, V)>,
+}
+
+impl<'m, K: Hash + Eq + Clone, V> ExternalMapReadGuard<'m, K, V> {
+    /// Iterate over references to the items under `key`, in insertion order. If the key is not
+    /// in the map, the iterator is empty.
+    pub fn get<'g, Q>(&'g self, key: &'g Q) -> ExternalMapIter<'g, K, V, Q>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized
+    {
+        ExternalMapIter { entries: self.entries.find_by(&self.map.by_key, key) }
+    }
+
+    /// Returns true if there are items under `key`.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.get(key).next().is_some()
+    }
+
+    /// The keys that have items in the map, in unspecified order.
+    pub fn keys(&self) -> Vec<K> {
+        let mut keys = HashSet::new();
+        self.entries.iter()
+            .map(|entry| &entry.0)
+            .filter(|&key| keys.insert(key))
+            .cloned()
+            .collect()
+    }
+
+    /// Iterate over references to all items in the map, regardless of key, in insertion order.
+    pub fn values<'g>(&'g self) -> ExternalMapValues<'g, K, V> {
+        ExternalMapValues { entries: self.entries.iter() }
+    }
+}
+
+/// Iterator over the items under a key in an ExternalMap.
+pub struct ExternalMapIter<'g, K: 'g, V: 'g, Q: 'g + ?Sized = K> {
+    entries: FindBy<'g, (K, V), K, Q>,
+}
+
+impl<'g, K: Borrow<Q>, V, Q: Eq + ?Sized> Iterator for ExternalMapIter<'g, K, V, Q> {
+    type Item = &'g V;
+
+    fn next(&mut self) -> Option<&'g V> {
+        self.entries.next().map(|entry| &entry.1)
+    }
+}
+
+/// Iterator over all items in an ExternalMap.
+pub struct ExternalMapValues<'g, K: 'g, V: 'g> {
+    entries: ExternalSetIter<'g, (K, V)>,
+}
+
+impl<'g, K, V> Iterator for ExternalMapValues<'g, K, V> {
+    type Item = &'g V;
+
+    fn next(&mut self) -> Option<&'g V> {
+        self.entries.next().map(|entry| &entry.1)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl<'g, K, V> ExactSizeIterator for ExternalMapValues<'g, K, V> {}
+
+/// The owner of an item in an ExternalMap
+pub struct MapItemOwner<'m, K: 'm, V: 'm> {
+    owner: ItemOwner<'m, (K, V)>,
+}
+
+impl<'m, K, V> MapItemOwner<'m, K, V> {
+    /// The key the item was inserted under.
+    pub fn key(&self) -> &K {
+        &self.owner.0
+    }
+
+    /// Remove the item from the map and return it.
+    ///
+    /// Panics if the current thread holds a read guard on the map.
+    pub fn take(self) -> V {
+        self.owner.take().1
+    }
+}
+
+impl<'m, K, V> Deref for MapItemOwner<'m, K, V> {
+    type Target = V;
+    fn deref(&self) -> &V {
+        &self.owner.1
+    }
+}
+
+#[test]
+fn test_map() {
+    let m = ExternalMap::<&str, u32>::new();
+    let a1 = m.insert("a", 1);
+    let b2 = m.insert("b", 2);
+    let a3 = m.insert("a", 3);
+    assert_eq!(*a3.key(), "a");
+
+    {
+        let guard = m.lock();
+        assert_eq!(guard.get("a").cloned().collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(guard.get("b").cloned().collect::<Vec<_>>(), vec![2]);
+        assert_eq!(guard.get("c").count(), 0);
+        assert_eq!(guard.values().len(), 3);
+        assert_eq!(guard.values().cloned().collect::<Vec<_>>(), vec![1, 2, 3]);
+        let mut keys = guard.keys();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b"]);
+    }
+
+    drop(a1);
+    assert_eq!(m.lock().get("a").cloned().collect::<Vec<_>>(), vec![3]);
+    assert_eq!(a3.take(), 3);
+    assert!(!m.lock().contains_key("a"));
+    assert_eq!(m.lock().keys(), vec!["b"]);
+
+    {
+        // Dropping an owner or inserting while iterating is deferred as in ExternalSet, and
+        // lookups by key stay consistent with iteration until the guard is released
+        let guard = m.lock();
+        let mut owners = vec![b2];
+        for _ in guard.get("b") {
+            owners.clear();
+            owners.push(m.insert("c", 4));
+        }
+        assert_eq!(guard.get("b").cloned().collect::<Vec<_>>(), vec![2]);
+        assert_eq!(guard.get("c").count(), 0);
+        assert_eq!(guard.keys(), vec!["b"]);
+        drop(guard);
+        assert_eq!(m.lock().keys(), vec!["c"]);
+        assert_eq!(m.lock().get("c").cloned().collect::<Vec<_>>(), vec![4]);
+        assert_eq!(m.lock().get("b").count(), 0);
+    }
+}
@@ -0,0 +1,191 @@
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// A set of externally-owned items that readers access through immutable snapshots.
+///
+/// Unlike ExternalSet, iterating never blocks insertion or removal: `snapshot()` returns the
+/// current version of the membership list, and every change publishes a new copy of the list.
+/// Items removed while a snapshot is live remain alive until the snapshot is released.
+///
+/// This trades a copy of the membership list on every insertion and removal for reads that only
+/// briefly lock to clone a reference count, so it suits sets that are iterated much more often
+/// than they change.
+pub struct SnapshotSet<T> {
+    current: Mutex<Arc<Vec<Entry<T>>>>,
+    next_seq: Mutex<u64>,
+}
+
+struct Entry<T> {
+    seq: u64,
+    item: Arc<T>,
+}
+
+impl<T> Clone for Entry<T> {
+    fn clone(&self) -> Entry<T> {
+        Entry { seq: self.seq, item: self.item.clone() }
+    }
+}
+
+impl<T> SnapshotSet<T> {
+    /// Create an empty SnapshotSet
+    pub fn new() -> SnapshotSet<T> {
+        SnapshotSet { current: Mutex::new(Arc::new(Vec::new())), next_seq: Mutex::new(0) }
+    }
+
+    /// Add an item to the collection, returning a SnapshotItemOwner to own it.
+    /// When the SnapshotItemOwner is dropped, the value will be removed from the collection.
+    pub fn insert<'s>(&'s self, item: T) -> SnapshotItemOwner<'s, T> {
+        let item = Arc::new(item);
+        let seq = self.update(|entries, seq| {
+            entries.push(Entry { seq, item: item.clone() });
+            seq + 1
+        });
+        SnapshotItemOwner { collection: self, seq, item }
+    }
+
+    /// Get the current version of the collection. Later insertions and removals do not affect
+    /// the returned snapshot.
+    pub fn snapshot(&self) -> Snapshot<T> {
+        Snapshot(self.current.lock().unwrap().clone())
+    }
+
+    fn remove(&self, seq: u64) {
+        self.update(|entries, next_seq| {
+            entries.retain(|e| e.seq != seq);
+            next_seq
+        });
+    }
+
+    /// Copy the current list, modify it, and publish the result. Writers are serialized by the
+    /// `next_seq` lock, so the `current` lock is only held to take a reference to the current
+    /// version and to swap in the new one, not while copying. Returns the sequence number that
+    /// was assigned before the update.
+    fn update<F: FnOnce(&mut Vec<Entry<T>>, u64) -> u64>(&self, f: F) -> u64 {
+        let mut next_seq = self.next_seq.lock().unwrap();
+        let current = self.current.lock().unwrap().clone();
+        let mut entries = (*current).clone();
+        let seq = *next_seq;
+        *next_seq = f(&mut entries, seq);
+        *self.current.lock().unwrap() = Arc::new(entries);
+        seq
+    }
+}
+
+impl<T> Default for SnapshotSet<T> {
+    fn default() -> SnapshotSet<T> {
+        SnapshotSet::new()
+    }
+}
+
+/// An immutable version of the items in a SnapshotSet.
+pub struct Snapshot<T>(Arc<Vec<Entry<T>>>);
+
+impl<T> Snapshot<T> {
+    /// Iterate over references to items in the snapshot, in insertion order.
+    pub fn iter(&self) -> SnapshotIter<'_, T> {
+        SnapshotIter { iter: self.0.iter(), except: None }
+    }
+
+    /// Iterate over references to items in the snapshot, excluding the one owned by the
+    /// provided SnapshotItemOwner. If it owns an item of another SnapshotSet, all items are
+    /// yielded.
+    pub fn others(&self, except: &SnapshotItemOwner<T>) -> SnapshotIter<'_, T> {
+        SnapshotIter { iter: self.0.iter(), except: Some(except.item.clone()) }
+    }
+}
+
+impl<T> Clone for Snapshot<T> {
+    fn clone(&self) -> Snapshot<T> {
+        Snapshot(self.0.clone())
+    }
+}
+
+/// Iterator over the items in a Snapshot.
+pub struct SnapshotIter<'a, T: 'a> {
+    iter: ::std::slice::Iter<'a, Entry<T>>,
+    except: Option<Arc<T>>,
+}
+
+impl<'a, T> Iterator for SnapshotIter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let except = &self.except;
+        self.iter.by_ref()
+            .find(|e| match *except {
+                Some(ref except) => !Arc::ptr_eq(&e.item, except),
+                None => true,
+            })
+            .map(|e| &*e.item)
+    }
+}
+
+/// The owner of an item in a SnapshotSet
+pub struct SnapshotItemOwner<'s, T: 's> {
+    collection: &'s SnapshotSet<T>,
+    seq: u64,
+    item: Arc<T>,
+}
+
+impl<'s, T> SnapshotItemOwner<'s, T> {
+    /// Remove the item from the collection and return it.
+    ///
+    /// Snapshots taken before the removal may still refer to the item, so it is returned as an
+    /// `Arc`. Once those snapshots are released, `Arc::try_unwrap` will recover the value.
+    pub fn take(self) -> Arc<T> {
+        self.collection.remove(self.seq);
+        let item = unsafe { ::std::ptr::read(&self.item) };
+        ::std::mem::forget(self);
+        item
+    }
+}
+
+impl<'s, T> Drop for SnapshotItemOwner<'s, T> {
+    fn drop(&mut self) {
+        self.collection.remove(self.seq);
+    }
+}
+
+impl<'s, T> Deref for SnapshotItemOwner<'s, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.item
+    }
+}
+
+#[test]
+fn test_snapshot() {
+    let c = SnapshotSet::<u32>::new();
+    let i1 = c.insert(1);
+    let i2 = c.insert(2);
+
+    let s = c.snapshot();
+    let i3 = c.insert(3);
+    drop(i1);
+    assert_eq!(s.iter().cloned().collect::<Vec<_>>(), vec![1, 2]);
+    assert_eq!(c.snapshot().iter().cloned().collect::<Vec<_>>(), vec![2, 3]);
+    assert_eq!(c.snapshot().others(&i3).cloned().collect::<Vec<_>>(), vec![2]);
+
+    // An owner from another set with the same sequence number excludes nothing
+    let other = SnapshotSet::<u32>::new();
+    let others = (0..3).map(|i| other.insert(i)).collect::<Vec<_>>();
+    assert_eq!(c.snapshot().others(&others[2]).cloned().collect::<Vec<_>>(), vec![2, 3]);
+
+    let taken = i2.take();
+    assert_eq!(Arc::strong_count(&taken), 2);
+    drop(s);
+    assert_eq!(Arc::try_unwrap(taken).ok(), Some(2));
+    assert_eq!(c.snapshot().iter().cloned().collect::<Vec<_>>(), vec![3]);
+}
+
+#[test]
+fn test_snapshot_iter_does_not_block() {
+    let c = SnapshotSet::<u32>::new();
+    let _i1 = c.insert(1);
+    let s = c.snapshot();
+    for _ in s.iter() {
+        // Membership changes proceed while the snapshot is held
+        let i2 = c.insert(2);
+        drop(i2);
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+
+use futures_core::Stream;
+
+use {EitherOwner, ExternalSet};
+
+/// A publish/subscribe hub whose subscribers are async `Stream`s.
+///
+/// Each subscriber has an unbounded queue that receives a clone of every published message, and
+/// the task polling the stream is woken when a message arrives. When the hub is dropped, every
+/// stream ends once its queued messages have been received.
+pub struct StreamHub<T> {
+    slots: Arc<ExternalSet<Slot<T>>>,
+}
+
+struct Slot<T>(Mutex<SlotState<T>>);
+
+struct SlotState<T> {
+    messages: VecDeque<T>,
+    waker: Option<Waker>,
+    /// Set when the hub is dropped.
+    closed: bool,
+}
+
+impl<T> Slot<T> {
+    fn new() -> Slot<T> {
+        Slot(Mutex::new(SlotState { messages: VecDeque::new(), waker: None, closed: false }))
+    }
+
+    fn state(&self) -> MutexGuard<'_, SlotState<T>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T> StreamHub<T> {
+    /// Create a StreamHub with no subscribers
+    pub fn new() -> StreamHub<T> {
+        StreamHub { slots: Arc::new(ExternalSet::new()) }
+    }
+
+    /// Subscribe to messages published after this call. The subscription ends when the returned
+    /// stream is dropped.
+    pub fn subscribe(&self) -> StreamSubscription<'_, T> {
+        StreamSubscription { slot: EitherOwner::Borrowed(self.slots.insert(Slot::new())) }
+    }
+
+    /// Like `subscribe`, but the stream does not borrow the hub, so it can be moved into a
+    /// spawned task.
+    pub fn subscribe_owned(&self) -> StreamSubscription<'static, T> where T: 'static {
+        StreamSubscription { slot: EitherOwner::Arc(self.slots.insert_arc(Slot::new())) }
+    }
+
+    /// Send a clone of the message to every subscriber, waking their tasks. Returns the number
+    /// of subscribers.
+    pub fn publish(&self, message: T) -> usize where T: Clone {
+        let slots = self.slots.lock();
+        let mut count = 0;
+        for slot in slots.iter() {
+            let waker = {
+                let mut state = slot.state();
+                state.messages.push_back(message.clone());
+                state.waker.take()
+            };
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+            count += 1;
+        }
+        count
+    }
+}
+
+impl<T> Drop for StreamHub<T> {
+    fn drop(&mut self) {
+        for slot in self.slots.lock().iter() {
+            let waker = {
+                let mut state = slot.state();
+                state.closed = true;
+                state.waker.take()
+            };
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> Default for StreamHub<T> {
+    fn default() -> StreamHub<T> {
+        StreamHub::new()
+    }
+}
+
+/// A subscription to a StreamHub, yielding each published message.
+///
+/// The stream ends only when the hub is dropped; drop it to unsubscribe.
+pub struct StreamSubscription<'h, T: 'h> {
+    slot: EitherOwner<'h, Slot<T>>,
+}
+
+impl<'h, T> Stream for StreamSubscription<'h, T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let mut state = self.slot.state();
+        match state.messages.pop_front() {
+            Some(message) => Poll::Ready(Some(message)),
+            None if state.closed => Poll::Ready(None),
+            None => {
+                match state.waker {
+                    Some(ref waker) if waker.will_wake(cx.waker()) => {}
+                    _ => state.waker = Some(cx.waker().clone()),
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn block_on_next<S: Stream + Unpin>(stream: &mut S) -> Option<S::Item> {
+    ::block_on(::std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)))
+}
+
+#[test]
+fn test_stream() {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    let hub = Arc::new(StreamHub::<u32>::new());
+    let mut s1 = hub.subscribe();
+    let mut s2 = hub.subscribe();
+    assert_eq!(hub.publish(1), 2);
+    assert_eq!(block_on_next(&mut s1), Some(1));
+
+    let h = hub.clone();
+    let t = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        h.publish(2)
+    });
+    assert_eq!(block_on_next(&mut s1), Some(2));
+    assert_eq!(t.join().unwrap(), 2);
+
+    drop(s1);
+    assert_eq!(hub.publish(3), 1);
+    assert_eq!(block_on_next(&mut s2), Some(1));
+    assert_eq!(block_on_next(&mut s2), Some(2));
+    assert_eq!(block_on_next(&mut s2), Some(3));
+}
+
+#[test]
+fn test_stream_owned() {
+    use std::thread;
+
+    let hub = StreamHub::<u32>::new();
+    let mut s = hub.subscribe_owned();
+    let t = thread::spawn(move || {
+        let mut messages = Vec::new();
+        while let Some(message) = block_on_next(&mut s) {
+            messages.push(message);
+        }
+        messages
+    });
+    assert_eq!(hub.publish(1), 1);
+    assert_eq!(hub.publish(2), 1);
+
+    // Dropping the hub ends the stream once the queued messages have been received
+    drop(hub);
+    assert_eq!(t.join().unwrap(), vec![1, 2]);
+}